@@ -0,0 +1,273 @@
+use crate::decoder;
+use crate::save_data::TimePoint;
+use std::path::Path;
+
+// Frames per envelope value, about 6ms at 44.1kHz
+const HOP_SIZE: usize = 256;
+// Spacing of the points in the time map
+const ANCHOR_SECONDS: f64 = 5.0;
+// Length of audio compared around each point
+const WINDOW_SECONDS: f64 = 4.0;
+// Furthest a version is expected to drift from its nominal position
+const MAX_DRIFT_SECONDS: f64 = 0.5;
+// Weakest correlation trusted as a match
+const MIN_CORRELATION: f64 = 0.5;
+
+/// Finds where points of the original land in a speed version.
+///
+/// A stretched waveform does not line up with the original sample for
+/// sample, so both are reduced to onset envelopes, and the version's
+/// envelope is squeezed back to the original's timing. Windows of the two
+/// are then cross-correlated every few seconds to find the drift from the
+/// nominal position. Passages without a clear match, like silence, are left
+/// out of the map.
+pub fn align(original: &Path, version: &Path, speed: f32) -> Result<Vec<TimePoint>, String> {
+    let (original_envelope, sample_rate) = onset_envelope(original)?;
+    let (version_envelope, version_rate) = onset_envelope(version)?;
+    if sample_rate != version_rate {
+        return Err("Speed version has a different sample rate".to_string());
+    }
+
+    let speed = speed as f64;
+    // The version's envelope at the original's hops, so index i of both
+    // holds the same point in the music if there is no drift
+    let squeezed: Vec<f64> = (0..original_envelope.len())
+        .map(|i| squeeze(&version_envelope, i as f64 * speed, speed))
+        .collect();
+
+    let hops_per_second = sample_rate as f64 / HOP_SIZE as f64;
+    let anchor_step = (ANCHOR_SECONDS * hops_per_second) as usize;
+    let half_window = (WINDOW_SECONDS * hops_per_second / 2.0) as usize;
+    let max_lag = (MAX_DRIFT_SECONDS * hops_per_second) as isize;
+
+    let mut time_map: Vec<TimePoint> = Vec::new();
+    let mut anchor = half_window;
+    while anchor + half_window < original_envelope.len() {
+        let window = &original_envelope[anchor - half_window..anchor + half_window];
+        if let Some(lag) = best_lag(window, &squeezed, anchor - half_window, max_lag) {
+            let point = TimePoint {
+                original: anchor as f64 / hops_per_second,
+                version: (anchor as f64 + lag) * speed / hops_per_second,
+            };
+            // Keep the map strictly increasing both ways so it can be inverted
+            if time_map
+                .last()
+                .is_none_or(|last| point.version > last.version)
+            {
+                time_map.push(point);
+            }
+        }
+        anchor += anchor_step;
+    }
+
+    Ok(time_map)
+}
+
+// Rises in loudness per hop, which mark note onsets
+fn onset_envelope(path: &Path) -> Result<(Vec<f64>, usize), String> {
+    let mut source = decoder::open(path)?;
+    let spec = source.spec();
+    let mut buffer = vec![0.0; HOP_SIZE * spec.channels];
+    let mut envelope = Vec::new();
+    let mut previous: Option<f64> = None;
+
+    loop {
+        let frames = source.read_frames(&mut buffer);
+        if frames == 0 {
+            break;
+        }
+        let energy: f64 = buffer[..frames * spec.channels]
+            .iter()
+            .map(|&sample| (sample as f64).powi(2))
+            .sum();
+        let level = (energy / frames as f64 + 1e-10).log10();
+        envelope.push(previous.map_or(0.0, |previous| (level - previous).max(0.0)));
+        previous = Some(level);
+        if frames < HOP_SIZE {
+            break;
+        }
+    }
+
+    Ok((envelope, spec.sample_rate))
+}
+
+// The value covering `step` values from `position`. Onsets only last a hop,
+// so when there are several values to cover the strongest is kept rather
+// than one picked that may miss it
+fn squeeze(values: &[f64], position: f64, step: f64) -> f64 {
+    if step <= 1.0 {
+        return interpolate(values, position);
+    }
+    let start = (position.round() as usize).min(values.len());
+    let end = ((position + step).round() as usize).min(values.len());
+    values[start..end].iter().copied().fold(0.0, f64::max)
+}
+
+fn interpolate(values: &[f64], position: f64) -> f64 {
+    let index = position as usize;
+    let fraction = position - index as f64;
+    match (values.get(index), values.get(index + 1)) {
+        (Some(a), Some(b)) => a + (b - a) * fraction,
+        (Some(a), None) => *a,
+        _ => 0.0,
+    }
+}
+
+// Offset in hops, to a fraction of a hop, at which `other` best matches
+// `window`, which starts at `start`
+fn best_lag(window: &[f64], other: &[f64], start: usize, max_lag: isize) -> Option<f64> {
+    let correlations: Vec<f64> = (-max_lag..=max_lag)
+        .map(|lag| {
+            let from = start as isize + lag;
+            if from < 0 || from as usize + window.len() > other.len() {
+                return f64::NEG_INFINITY;
+            }
+            let from = from as usize;
+            correlation(window, &other[from..from + window.len()])
+        })
+        .collect();
+
+    let (best, &peak) = correlations
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    if peak < MIN_CORRELATION {
+        return None;
+    }
+
+    // Fit a parabola through the peak and its neighbours
+    let mut offset = 0.0;
+    if best > 0 && best + 1 < correlations.len() {
+        let (before, after) = (correlations[best - 1], correlations[best + 1]);
+        let curvature = before - 2.0 * peak + after;
+        if before.is_finite() && after.is_finite() && curvature < 0.0 {
+            offset = 0.5 * (before - after) / curvature;
+        }
+    }
+    Some(best as f64 - max_lag as f64 + offset)
+}
+
+// Normalized cross-correlation of two windows of equal length
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / a.len() as f64;
+    let mean_b = b.iter().sum::<f64>() / b.len() as f64;
+    let (mut product, mut power_a, mut power_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x - mean_a, y - mean_b);
+        product += x * y;
+        power_a += x * x;
+        power_b += y * y;
+    }
+    if power_a <= 0.0 || power_b <= 0.0 {
+        return 0.0;
+    }
+    product / (power_a * power_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Onsets at irregular hops, so only one lag lines them up
+    fn envelope(length: usize, offset: usize) -> Vec<f64> {
+        let mut envelope = vec![0.0; length];
+        let mut position = 7;
+        let mut gap = 11;
+        while position + offset < length {
+            envelope[position + offset] = 1.0;
+            envelope[position + offset + 1..(position + offset + 3).min(length)].fill(0.4);
+            position += gap;
+            gap = gap * 7 % 23 + 9;
+        }
+        envelope
+    }
+
+    #[test]
+    fn finds_constant_offset() {
+        let original = envelope(2000, 0);
+        let delayed = envelope(2000, 13);
+        let window = &original[500..1100];
+        let lag = best_lag(window, &delayed, 500, 40).unwrap();
+        assert!((lag - 13.0).abs() < 0.01, "lag {}", lag);
+    }
+
+    #[test]
+    fn ignores_windows_without_a_match() {
+        let original = envelope(2000, 0);
+        let silence = vec![0.0; 2000];
+        assert_eq!(best_lag(&original[500..1100], &silence, 500, 40), None);
+    }
+
+    #[test]
+    fn correlation_is_normalized() {
+        let a = [0.0, 1.0, 0.5, 0.0, 2.0];
+        let b: Vec<f64> = a.iter().map(|x| x * 3.0 + 1.0).collect();
+        assert!((correlation(&a, &b) - 1.0).abs() < 1e-12);
+        let inverted: Vec<f64> = a.iter().map(|x| -x).collect();
+        assert!((correlation(&a, &inverted) + 1.0).abs() < 1e-12);
+        assert_eq!(correlation(&a, &[1.0; 5]), 0.0);
+    }
+
+    #[test]
+    fn interpolates_between_values() {
+        let values = [0.0, 2.0, 4.0];
+        assert_eq!(interpolate(&values, 0.5), 1.0);
+        assert_eq!(interpolate(&values, 2.0), 4.0);
+        assert_eq!(interpolate(&values, 3.5), 0.0);
+    }
+
+    #[test]
+    fn squeeze_keeps_short_peaks() {
+        let values = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+        let squeezed: Vec<f64> = (0..3)
+            .map(|i| squeeze(&values, i as f64 * 2.0, 2.0))
+            .collect();
+        assert_eq!(squeezed, [0.0, 1.0, 0.0]);
+        assert_eq!(squeeze(&values, 2.5, 0.5), 0.5);
+    }
+
+    fn write_clicks(path: &Path, onsets: impl Iterator<Item = f64>, seconds: f64) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 22050,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut samples = vec![0.0f32; (seconds * 22050.0) as usize];
+        for onset in onsets {
+            let start = (onset * 22050.0) as usize;
+            for (i, sample) in samples[start..].iter_mut().take(2000).enumerate() {
+                *sample += (-(i as f32) / 300.0).exp() * (i as f32 * 0.3).sin();
+            }
+        }
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn aligns_version_with_constant_delay() {
+        let onsets: Vec<f64> = (0..60)
+            .map(|i| 0.3 + i as f64 * 0.33 + (i % 3) as f64 * 0.07)
+            .collect();
+        let directory = std::env::temp_dir();
+        let original = directory.join("transcriber_test_align_original.wav");
+        let version = directory.join("transcriber_test_align_version.wav");
+        write_clicks(&original, onsets.iter().copied(), 21.0);
+        write_clicks(&version, onsets.iter().map(|t| t * 2.0 + 0.03), 42.0);
+
+        let time_map = align(&original, &version, 2.0).unwrap();
+        assert!(time_map.len() >= 3);
+        for point in time_map {
+            let drift = point.version - point.original * 2.0;
+            assert!(
+                (drift - 0.03).abs() < 0.006,
+                "drift {} at {}",
+                drift,
+                point.original
+            );
+        }
+    }
+}
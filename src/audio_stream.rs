@@ -1,146 +1,236 @@
-use crate::save_data::{SongData, SpeedVersion};
-use hound::WavReader;
+use crate::bookmarks::{Bookmark, BookmarkColor, Bookmarks};
+use crate::channel_matrix::ChannelMatrix;
+use crate::decoder::{self, Decoder};
+use crate::equalizer::{Equalizer, EqualizerSettings};
+use crate::looping::{FollowAction, GapUnit, Interlude, LoopSection, LoopSettings, Trainer};
+use crate::memory_decoder::MemoryDecoder;
+use crate::pan_extract::PanExtract;
+use crate::save_data::{Layer, SongData, SpeedVersion};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::fs;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::path::PathBuf;
-
-pub enum Digits {
-    One,
-    Two,
-    Three,
-    Four,
-    Five,
-    Six,
-    Seven,
-    Eight,
-    Nine,
-    Zero,
-}
+use std::path::Path;
+
+// Range of the live tempo applied on top of the playing speed version
+const MIN_LIVE_TEMPO: f64 = 0.25;
+const MAX_LIVE_TEMPO: f64 = 1.5;
+// Transpose range either way, in cents
+const MAX_TRANSPOSE_CENTS: i32 = 1200;
+// How far either side of a new loop point to look for a zero crossing
+const ZERO_CROSSING_SEARCH_SECONDS: f32 = 0.005;
+// Time taken to fade between the full mix and a separated layer
+const LAYER_CROSSFADE_SECONDS: f32 = 0.1;
+// Time taken to fade from one speed version into another
+const SPEED_CROSSFADE_SECONDS: f32 = 0.05;
 
+#[derive(Clone, Default)]
 pub struct AudioStreamOutputData {
     pub current_time: String,
     pub loop_start: String,
     pub loop_end: String,
     pub is_looping: String,
+    pub loop_interlude: String,
+    pub loop_section: String,
+    pub loop_iteration: String,
+    pub trainer: String,
     pub current_speed: SpeedVersion,
+    pub error: String,
     pub next_fastest_speed: Option<SpeedVersion>,
     pub next_slowest_speed: Option<SpeedVersion>,
-    pub bookmark_1: String,
-    pub bookmark_2: String,
-    pub bookmark_3: String,
-    pub bookmark_4: String,
-    pub bookmark_5: String,
-    pub bookmark_6: String,
-    pub bookmark_7: String,
-    pub bookmark_8: String,
-    pub bookmark_9: String,
-    pub bookmark_0: String,
+    pub live_tempo: String,
+    pub live_tempo_mode: String,
+    pub transpose: String,
+    pub pan_extract: String,
+    pub channel_mode: String,
+    pub equalizer_preset: String,
+    pub layer: String,
+    pub bookmarks: Vec<BookmarkOutputData>,
+    pub bookmark_note: String,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Bookmarks {
-    bookmark_1: f32,
-    bookmark_2: f32,
-    bookmark_3: f32,
-    bookmark_4: f32,
-    bookmark_5: f32,
-    bookmark_6: f32,
-    bookmark_7: f32,
-    bookmark_8: f32,
-    bookmark_9: f32,
-    bookmark_0: f32,
+#[derive(Clone, Default)]
+pub struct BookmarkOutputData {
+    pub label: String,
+    pub color: BookmarkColor,
+    pub time: String,
+    pub slot: Option<u8>,
+    pub selected: bool,
 }
 
-impl Bookmarks {
-    pub fn new() -> Self {
-        Bookmarks {
-            bookmark_1: 0.0,
-            bookmark_2: 0.0,
-            bookmark_3: 0.0,
-            bookmark_4: 0.0,
-            bookmark_5: 0.0,
-            bookmark_6: 0.0,
-            bookmark_7: 0.0,
-            bookmark_8: 0.0,
-            bookmark_9: 0.0,
-            bookmark_0: 0.0,
-        }
-    }
-
-    pub fn set_bookmark(&mut self, bookmark: Digits, sample: f32) {
-        match bookmark {
-            Digits::One => self.bookmark_1 = sample,
-            Digits::Two => self.bookmark_2 = sample,
-            Digits::Three => self.bookmark_3 = sample,
-            Digits::Four => self.bookmark_4 = sample,
-            Digits::Five => self.bookmark_5 = sample,
-            Digits::Six => self.bookmark_6 = sample,
-            Digits::Seven => self.bookmark_7 = sample,
-            Digits::Eight => self.bookmark_8 = sample,
-            Digits::Nine => self.bookmark_9 = sample,
-            Digits::Zero => self.bookmark_0 = sample,
-        }
-    }
-
-    pub fn get_bookmark(&self, bookmark: Digits) -> f32 {
-        match bookmark {
-            Digits::One => self.bookmark_1,
-            Digits::Two => self.bookmark_2,
-            Digits::Three => self.bookmark_3,
-            Digits::Four => self.bookmark_4,
-            Digits::Five => self.bookmark_5,
-            Digits::Six => self.bookmark_6,
-            Digits::Seven => self.bookmark_7,
-            Digits::Eight => self.bookmark_8,
-            Digits::Nine => self.bookmark_9,
-            Digits::Zero => self.bookmark_0,
-        }
+#[derive(Serialize, Deserialize, Default)]
+pub struct Transpose {
+    semitones: i32,
+    cents: i32,
+}
+
+impl Transpose {
+    fn total_cents(&self) -> i32 {
+        self.semitones * 100 + self.cents
+    }
+
+    /// Shifts by a number of cents, carrying into semitones so cents stay
+    /// within half a semitone either way.
+    pub fn shift(&mut self, cents: i32) {
+        let total = (self.total_cents() + cents).clamp(-MAX_TRANSPOSE_CENTS, MAX_TRANSPOSE_CENTS);
+        self.semitones = (total as f32 / 100.0).round() as i32;
+        self.cents = total - self.semitones * 100;
+    }
+
+    /// Frequency ratio of the shift, e.g. 2.0 for an octave up.
+    pub fn ratio(&self) -> f64 {
+        2f64.powf(self.total_cents() as f64 / 1200.0)
+    }
+}
+
+// A separated layer played in step with the full mix
+struct LayerSource {
+    layer: Layer,
+    decoder: Box<dyn Decoder>,
+    gain: f32,
+}
+
+// The speed version switched away from, still playing while the new one
+// fades in
+struct VersionFade {
+    index: usize,
+    decoder: Box<dyn Decoder>,
+    done: u64,
+    frame: Vec<f32>,
+}
+
+fn fade_towards(gain: f32, target: f32, step: f32) -> f32 {
+    if gain < target {
+        (gain + step).min(target)
+    } else {
+        (gain - step).max(target)
     }
 }
 
 pub struct AudioStream {
-    file: BufReader<File>,
+    decoder: Box<dyn Decoder>,
     pub channels: usize,
-    bytes_per_sample: usize,
     pub sample_rate: usize,
     paused: bool,
     is_looping: bool,
-    loop_sample_start: f32,
-    loop_sample_end: f32,
+    // Loop points in seconds of the original recording
+    loop_start: f64,
+    loop_end: f64,
+    loop_crossfade_seconds: f32,
+    snap_loop_points: bool,
+    // The start of the loop, read ahead to crossfade into at the loop end
+    loop_head: Vec<f32>,
+    loop_settings: LoopSettings,
+    // Every saved loop; the one being played and edited is copied into the
+    // fields above
+    loop_sections: Vec<LoopSection>,
+    loop_section: usize,
+    // Playing between repetitions of the loop
+    interlude: Option<Interlude>,
+    // Frames faded in so far and the fade length, after an interlude
+    loop_fade_in: Option<(u64, u64)>,
+    // Repetition of the loop playing now, counting from 1
+    loop_iteration: u32,
+    trainer: Option<Trainer>,
     bookmarks: Bookmarks,
+    // Bookmark last jumped to or set, which edits apply to
+    selected_bookmark: Option<usize>,
     song_data: SongData,
     current_speed: SpeedVersion,
+    // Tempo applied in real time by the engine's time stretch stage
+    live_tempo: f64,
+    // Apply the live tempo like a tape, moving pitch along with speed
+    varispeed: bool,
+    transpose: Transpose,
+    pan_extract: PanExtract,
+    channel_matrix: ChannelMatrix,
+    equalizer: Equalizer,
+    layers: Vec<LayerSource>,
+    // Layer being listened to, or the full mix when None
+    selected_layer: Option<Layer>,
+    full_mix_gain: f32,
+    layer_frame: Vec<f32>,
+    preload: bool,
+    // Speed versions opened ahead of time so a switch never waits on a file,
+    // indexed like `song_data.speed_versions`. The slot of the version
+    // playing, or fading out, is empty.
+    standby: Vec<Option<Box<dyn Decoder>>>,
+    outgoing: Option<VersionFade>,
+    // Why the last action failed, shown in the TUI
+    error: Option<String>,
 }
 
 impl AudioStream {
-    pub fn from_wave_file(file_path: &str) -> Self {
-        let reader = WavReader::open(&file_path).expect("Could not open file");
-        let wave_spec = reader.spec();
-        let wave_header_size = 44;
+    pub fn from_file(file_path: &str, preload: bool) -> Self {
+        Self::new(SongData::from_file(file_path), preload)
+    }
 
-        let file = File::open(&file_path).expect("Could not open");
-        let mut reader = BufReader::new(file);
+    fn new(song_data: SongData, preload: bool) -> Self {
+        let decoder = Self::open_decoder(&song_data.original_file_path, preload)
+            .expect("Could not open file");
+        let spec = decoder.spec();
 
-        reader
-            .seek(SeekFrom::Start(wave_header_size as u64))
-            .expect("Could not seek past header");
+        let samples_per_second = (spec.sample_rate * spec.channels) as f64;
+        let bookmarks = Bookmarks::load(
+            &song_data.song_dir.join("bookmarks.json"),
+            samples_per_second,
+        );
+        let loop_sections = Self::load_loop_sections(&song_data.song_dir);
+        let first_loop = &loop_sections[0];
+        let transpose = Self::load_transpose(&song_data.song_dir);
+        let equalizer_settings = Self::load_equalizer_settings(&song_data.song_dir);
 
-        let song_data = SongData::from_wave_file(file_path);
-        let bookmarks = Self::load_bookmarks(&song_data.song_dir);
+        // A layer that fails to open is left out, and the rest still play
+        let mut layers = Vec::new();
+        let mut error = None;
+        for version in &song_data.layer_versions {
+            match Self::open_decoder(&version.file_path, preload) {
+                Ok(decoder) => layers.push(LayerSource {
+                    layer: version.layer,
+                    decoder,
+                    gain: 0.0,
+                }),
+                Err(e) => {
+                    error = Some(format!(
+                        "Could not open layer {}: {}",
+                        version.file_path.display(),
+                        e
+                    ))
+                }
+            }
+        }
+
+        // A version that fails to open is tried again, and its error shown,
+        // when it is switched to
+        let standby = song_data
+            .speed_versions
+            .iter()
+            .map(|v| {
+                (v.speed != 1.0)
+                    .then(|| Self::open_decoder(&v.file_path, preload).ok())
+                    .flatten()
+            })
+            .collect();
 
         AudioStream {
-            file: reader,
-            channels: wave_spec.channels as usize,
-            bytes_per_sample: 2,
-            sample_rate: wave_spec.sample_rate as usize,
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            decoder,
             paused: false,
             is_looping: false,
-            loop_sample_start: 0.0,
-            loop_sample_end: 0.0,
+            loop_start: first_loop.start,
+            loop_end: first_loop.end,
+            loop_crossfade_seconds: 0.02,
+            snap_loop_points: false,
+            loop_head: Vec::new(),
+            loop_settings: first_loop.settings.clone(),
+            loop_sections,
+            loop_section: 0,
+            interlude: None,
+            loop_fade_in: None,
+            loop_iteration: 1,
+            trainer: None,
             bookmarks,
+            selected_bookmark: None,
             current_speed: song_data
                 .speed_versions
                 .iter()
@@ -148,16 +238,120 @@ impl AudioStream {
                 .expect("could not initialize initial speed")
                 .clone(),
             song_data,
+            live_tempo: 1.0,
+            varispeed: false,
+            transpose,
+            pan_extract: PanExtract::new(),
+            channel_matrix: ChannelMatrix::new(spec.sample_rate),
+            equalizer: Equalizer::new(spec.channels, spec.sample_rate, &equalizer_settings),
+            layers,
+            selected_layer: None,
+            full_mix_gain: 1.0,
+            layer_frame: vec![0.0; spec.channels],
+            preload,
+            standby,
+            outgoing: None,
+            error,
         }
     }
 
-    fn load_bookmarks(song_dir: &PathBuf) -> Bookmarks {
-        let bookmarks_path = song_dir.join("bookmarks.json");
-        if bookmarks_path.exists() {
-            let bookmarks_str = fs::read_to_string(&bookmarks_path).unwrap_or_default();
-            serde_json::from_str(&bookmarks_str).unwrap_or_else(|_| Bookmarks::new())
+    fn open_decoder(path: &Path, preload: bool) -> Result<Box<dyn Decoder>, String> {
+        if preload {
+            Ok(Box::new(MemoryDecoder::open(path)?))
         } else {
-            Bookmarks::new()
+            decoder::open(path)
+        }
+    }
+
+    fn load_transpose(song_dir: &Path) -> Transpose {
+        let transpose_path = song_dir.join("transpose.json");
+        fs::read_to_string(transpose_path)
+            .ok()
+            .and_then(|transpose_str| serde_json::from_str(&transpose_str).ok())
+            .unwrap_or_default()
+    }
+
+    fn load_loop_sections(song_dir: &Path) -> Vec<LoopSection> {
+        let loops_path = song_dir.join("loops.json");
+        let mut sections: Vec<LoopSection> = fs::read_to_string(loops_path)
+            .ok()
+            .and_then(|loops_str| serde_json::from_str(&loops_str).ok())
+            .unwrap_or_default();
+
+        if sections.is_empty() {
+            sections.push(LoopSection::new("Loop 1".to_string()));
+        }
+        sections
+    }
+
+    // Copies the loop being played into its section and saves every section
+    fn save_loop(&mut self) {
+        let section = &mut self.loop_sections[self.loop_section];
+        section.start = self.loop_start;
+        section.end = self.loop_end;
+        section.settings = self.loop_settings.clone();
+
+        let loops_path = self.song_data.song_dir.join("loops.json");
+        let loops_str = serde_json::to_string_pretty(&self.loop_sections).unwrap();
+        fs::write(loops_path, loops_str).expect("Could not write loops");
+    }
+
+    fn select_loop_section(&mut self, index: usize) {
+        self.loop_section = index;
+        let section = &self.loop_sections[index];
+        self.loop_start = section.start;
+        self.loop_end = section.end;
+        self.loop_settings = section.settings.clone();
+
+        self.loop_head.clear();
+        self.interlude = None;
+        self.loop_fade_in = None;
+        self.loop_iteration = 1;
+        // The trainer belongs to the loop it was started on
+        if let Some(trainer) = self.trainer.take() {
+            self.live_tempo = trainer.previous_live_tempo;
+        }
+    }
+
+    pub fn cycle_loop_section(&mut self, delta: i32) {
+        let count = self.loop_sections.len() as i32;
+        let index = (self.loop_section as i32 + delta).rem_euclid(count);
+        self.select_loop_section(index as usize);
+    }
+
+    /// Turns looping on for the selected section and jumps to its start.
+    pub fn activate_loop_section(&mut self) {
+        self.is_looping = true;
+        self.loop_iteration = 1;
+        let result = self.jump(self.frame_for_time(self.loop_start));
+        self.report(result);
+    }
+
+    /// Adds a section over the current loop points and selects it.
+    pub fn add_loop_section(&mut self) {
+        let mut section = LoopSection::new(format!("Loop {}", self.loop_sections.len() + 1));
+        section.start = self.loop_start;
+        section.end = self.loop_end;
+        self.loop_sections.push(section);
+        self.select_loop_section(self.loop_sections.len() - 1);
+        self.save_loop();
+    }
+
+    pub fn delete_loop_section(&mut self) {
+        self.loop_sections.remove(self.loop_section);
+        if self.loop_sections.is_empty() {
+            self.loop_sections
+                .push(LoopSection::new("Loop 1".to_string()));
+        }
+        self.is_looping = false;
+        self.select_loop_section(self.loop_section.min(self.loop_sections.len() - 1));
+        self.save_loop();
+    }
+
+    pub fn rename_loop_section(&mut self, name: String) {
+        if !name.trim().is_empty() {
+            self.loop_sections[self.loop_section].name = name.trim().to_string();
+            self.save_loop();
         }
     }
 
@@ -180,275 +374,1231 @@ impl AudioStream {
     }
 
     fn save_bookmarks(&self) {
-        let bookmarks_path = self.song_data.song_dir.join("bookmarks.json");
-        let bookmarks_str = serde_json::to_string_pretty(&self.bookmarks).unwrap();
-        fs::write(bookmarks_path, bookmarks_str).expect("Could not write bookmarks");
+        self.bookmarks
+            .save(&self.song_data.song_dir.join("bookmarks.json"));
     }
 
-    fn calculate_position_for_time(&self, time: f32, speed: f32) -> (u64, usize) {
-        // Calculate the target sample position, ensuring it's frame-aligned
-        let new_sample = (time * self.sample_rate as f32 * speed) as f32;
-        let frame_size = self.channels * self.bytes_per_sample;
-        let aligned_sample = (new_sample as usize / frame_size) * frame_size;
-
-        // Calculate the byte position, ensuring it's frame-aligned
-        let byte_position = (aligned_sample as u64 * self.bytes_per_sample as u64) + 44;
+    fn load_equalizer_settings(song_dir: &Path) -> EqualizerSettings {
+        let equalizer_path = song_dir.join("equalizer.json");
+        fs::read_to_string(equalizer_path)
+            .ok()
+            .and_then(|equalizer_str| serde_json::from_str(&equalizer_str).ok())
+            .unwrap_or_default()
+    }
 
-        (byte_position, aligned_sample)
+    fn save_equalizer_settings(&self) {
+        let equalizer_path = self.song_data.song_dir.join("equalizer.json");
+        let equalizer_str = serde_json::to_string_pretty(&self.equalizer.settings()).unwrap();
+        fs::write(equalizer_path, equalizer_str).expect("Could not write equalizer settings");
     }
 
-    fn get_seconds_for_sample_original(&self, sample: f32) -> f32 {
-        (sample as f32 / self.sample_rate as f32) as f32
+    fn save_transpose(&self) {
+        let transpose_path = self.song_data.song_dir.join("transpose.json");
+        let transpose_str = serde_json::to_string_pretty(&self.transpose).unwrap();
+        fs::write(transpose_path, transpose_str).expect("Could not write transpose");
     }
 
     pub fn output_data(&mut self) -> AudioStreamOutputData {
-        let current_time = self.get_current_time_seconds();
-        let loop_start = self.get_loop_start_seconds();
-        let loop_end = self.get_seconds_for_sample(self.loop_sample_end);
         let is_looping = self.is_looping;
 
         AudioStreamOutputData {
-            current_time: format!("{:.2}", current_time),
-            loop_start: format!("{:.2}", loop_start),
-            loop_end: format!("{:.2}", loop_end),
+            current_time: format!("{:.2}", self.current_time()),
+            loop_start: format!("{:.2}", self.loop_start),
+            loop_end: format!("{:.2}", self.loop_end),
             is_looping: format!("{}", is_looping),
+            loop_interlude: format!(
+                "gap {} count-in {} at {} bpm",
+                self.loop_settings.describe_gap(),
+                self.loop_settings.count_in_beats,
+                self.loop_settings.bpm
+            ),
+            loop_section: format!(
+                "[{}/{}] {}",
+                self.loop_section + 1,
+                self.loop_sections.len(),
+                self.loop_sections[self.loop_section].name
+            ),
+            loop_iteration: self.loop_settings.describe_iteration(self.loop_iteration),
+            trainer: match &self.trainer {
+                Some(trainer) => {
+                    let (_, fastest) = self.trainer_tempo_range();
+                    let limit = if self.loop_settings.trainer.target_tempo > fastest {
+                        format!(", this version tops out at {:.0}%", fastest * 100.0)
+                    } else {
+                        String::new()
+                    };
+                    format!(
+                        "{}{} ({})",
+                        trainer.describe(&self.loop_settings.trainer),
+                        limit,
+                        self.loop_settings.trainer.describe()
+                    )
+                }
+                None => format!("off ({})", self.loop_settings.trainer.describe()),
+            },
             current_speed: self.current_speed.clone(),
+            error: match &self.error {
+                Some(e) => format!(" ({})", e),
+                None => String::new(),
+            },
             next_fastest_speed: self.get_next_fastest_speed(),
             next_slowest_speed: self.get_next_slowest_speed(),
-            bookmark_1: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::One))
-            ),
-            bookmark_2: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Two))
-            ),
-            bookmark_3: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Three))
-            ),
-            bookmark_4: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Four))
-            ),
-            bookmark_5: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Five))
-            ),
-            bookmark_6: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Six))
-            ),
-            bookmark_7: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Seven))
-            ),
-            bookmark_8: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Eight))
-            ),
-            bookmark_9: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Nine))
-            ),
-            bookmark_0: format!(
-                "{:.2}",
-                self.get_seconds_for_sample_original(self.bookmarks.get_bookmark(Digits::Zero))
+            live_tempo: format!("{:.0}%", self.live_tempo * 100.0),
+            live_tempo_mode: if self.varispeed {
+                "varispeed".to_string()
+            } else {
+                "stretch".to_string()
+            },
+            pan_extract: self.pan_extract.description(),
+            channel_mode: self.channel_matrix.mode().name().to_string(),
+            equalizer_preset: self.equalizer.preset_name().to_string(),
+            layer: self.layer_name(),
+            transpose: format!(
+                "{:+} semitones {:+} cents",
+                self.transpose.semitones, self.transpose.cents
             ),
+            bookmarks: self
+                .bookmarks
+                .list()
+                .iter()
+                .enumerate()
+                .map(|(i, bookmark)| BookmarkOutputData {
+                    label: bookmark.label.clone(),
+                    color: bookmark.color,
+                    time: format!("{:.2}", bookmark.time),
+                    slot: bookmark.slot,
+                    selected: self.selected_bookmark == Some(i),
+                })
+                .collect(),
+            bookmark_note: self
+                .selected_bookmark
+                .and_then(|i| self.bookmarks.list()[i].note.clone())
+                .unwrap_or_default(),
         }
     }
 
-    pub fn set_bookmark(&mut self, bookmark: Digits) {
-        let sample = self.get_current_sample_location();
-        self.bookmarks.set_bookmark(bookmark, sample);
+    /// Puts the bookmark for a number key at the current position.
+    pub fn set_bookmark(&mut self, key: u8) {
+        let time = self.current_time();
+        self.selected_bookmark = Some(self.bookmarks.set(time, Some(key)));
         self.save_bookmarks();
     }
 
-    pub fn seek_to_bookmark(&mut self, bookmark: Digits) {
-        let bookmark_sample = self.bookmarks.get_bookmark(bookmark);
-        let bookmark_time = self.get_seconds_for_sample_original(bookmark_sample);
-        let (byte_position, _) =
-            self.calculate_position_for_time(bookmark_time, self.current_speed.speed);
+    /// Adds a bookmark without a number key at the current position.
+    pub fn add_bookmark(&mut self) {
+        let time = self.current_time();
+        self.selected_bookmark = Some(self.bookmarks.set(time, None));
+        self.save_bookmarks();
+    }
 
-        self.file
-            .seek(SeekFrom::Start(byte_position))
-            .expect("Could not seek to bookmark");
+    pub fn seek_to_bookmark(&mut self, key: u8) {
+        if let Some(index) = self.bookmarks.slot(key) {
+            let result = self.jump_to_bookmark(index);
+            self.report(result);
+        }
     }
 
-    pub fn toggle_play(&mut self) {
-        self.paused = !self.paused;
+    fn jump_to_bookmark(&mut self, index: usize) -> Result<(), String> {
+        let frame = self.frame_for_time(self.bookmarks.list()[index].time);
+        self.jump(frame)?;
+        self.selected_bookmark = Some(index);
+        Ok(())
     }
 
-    pub fn read_frame(&mut self) -> Vec<i16> {
-        let mut frame = vec![0i16; self.channels];
-        let mut buffer = vec![0u8; self.channels * self.bytes_per_sample];
+    pub fn next_bookmark(&mut self) {
+        // Past the frame a jump lands on, in case it rounded down
+        let time = self.time_for_frame(self.decoder.position() + 1);
+        if let Some(index) = self.bookmarks.next_after(time) {
+            let result = self.jump_to_bookmark(index);
+            self.report(result);
+        }
+    }
 
-        if self.is_looping && self.get_current_sample_location() > self.loop_sample_end {
-            self.seek_to_sample(self.loop_sample_start);
-            return frame;
+    /// Jumps to the bookmark before the current position, skipping one
+    /// that was only just passed so repeated presses keep going back.
+    pub fn previous_bookmark(&mut self) {
+        if let Some(index) = self.bookmarks.previous_before(self.current_time() - 1.0) {
+            let result = self.jump_to_bookmark(index);
+            self.report(result);
         }
+    }
+
+    pub fn label_bookmark(&mut self, label: String) {
+        if let Some(bookmark) = self.selected_bookmark_mut()
+            && !label.trim().is_empty()
+        {
+            bookmark.label = label.trim().to_string();
+            self.save_bookmarks();
+        }
+    }
+
+    /// Sets the note on the selected bookmark, or clears it if empty.
+    pub fn note_bookmark(&mut self, note: String) {
+        if let Some(bookmark) = self.selected_bookmark_mut() {
+            let note = note.trim();
+            bookmark.note = (!note.is_empty()).then(|| note.to_string());
+            self.save_bookmarks();
+        }
+    }
+
+    pub fn next_bookmark_color(&mut self) {
+        if let Some(bookmark) = self.selected_bookmark_mut() {
+            bookmark.color = bookmark.color.next();
+            self.save_bookmarks();
+        }
+    }
+
+    pub fn delete_bookmark(&mut self) {
+        if let Some(index) = self.selected_bookmark.take() {
+            self.bookmarks.remove(index);
+            self.save_bookmarks();
+        }
+    }
+
+    fn selected_bookmark_mut(&mut self) -> Option<&mut Bookmark> {
+        self.bookmarks.get_mut(self.selected_bookmark?)
+    }
+
+    pub fn toggle_play(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn read_frame(&mut self) -> Vec<f32> {
+        let mut frame = vec![0.0; self.channels];
 
         if self.paused {
             return frame;
         }
 
-        match self.file.read_exact(&mut buffer) {
-            Ok(_) => {
-                // Convert bytes to i16 samples (little endian)
-                for i in 0..self.channels {
-                    let idx = i * self.bytes_per_sample;
-                    frame[i] = i16::from_le_bytes([buffer[idx], buffer[idx + 1]]);
+        if let Some((_, end)) = self.loop_bounds()
+            && self.decoder.position() >= end
+        {
+            if self.final_repetition() {
+                self.finish_loop();
+                if self.paused {
+                    return frame;
                 }
+            } else if let Err(e) = self.restart_loop() {
+                self.stop_with_error(e);
+                return frame;
             }
-            Err(_) => {
-                // End of file or error
-                self.paused = true;
+        }
+
+        if let Some(interlude) = self.interlude.as_mut() {
+            // Clicks and silence skip the pan, channel and EQ stages below, so
+            // the count-in is heard the same whatever they are set to. The
+            // engine still stretches them, which keeps beats on the tempo.
+            if interlude.next_frame(&mut frame) {
+                return frame;
             }
+            self.interlude = None;
         }
 
+        self.read_source_frame(&mut frame);
+        self.fade_from_previous_version(&mut frame);
+        if let Err(e) = self.crossfade_loop_seam(&mut frame) {
+            self.stop_with_error(e);
+        }
+        self.fade_in_after_interlude(&mut frame);
+        self.pan_extract.apply(&mut frame);
+        self.channel_matrix.apply(&mut frame);
+        self.equalizer.apply(&mut frame);
+
         frame
     }
 
-    fn get_current_sample_location(&mut self) -> f32 {
-        ((self.file.stream_position().unwrap() - 44) / self.bytes_per_sample as u64) as f32
+    // Reads the next frame of the song, with any separated layers mixed in
+    fn read_source_frame(&mut self, frame: &mut [f32]) {
+        if !self.decoder.read_frame(frame) {
+            // End of file or error
+            self.paused = true;
+        }
+        self.mix_layers(frame);
     }
 
-    fn get_current_byte_location(&mut self) -> usize {
-        (self.file.stream_position().unwrap() - 44) as usize
+    fn loop_bounds(&self) -> Option<(u64, u64)> {
+        let start = self.frame_for_time(self.loop_start);
+        let end = self.frame_for_time(self.loop_end);
+        (self.is_looping && end > start).then_some((start, end))
     }
 
-    fn get_seconds_for_sample(&mut self, sample: f32) -> f32 {
-        (sample as f32 / self.sample_rate as f32) as f32 / self.current_speed.speed
+    // The loop, unless it plays through its end this time round
+    fn loop_frames(&self) -> Option<(u64, u64)> {
+        self.loop_bounds().filter(|_| !self.final_repetition())
     }
 
-    pub fn set_loop_start(&mut self) {
-        self.loop_sample_start = self.get_current_sample_location()
+    fn final_repetition(&self) -> bool {
+        let repetitions = self.loop_settings.repetitions;
+        repetitions > 0 && self.loop_iteration >= repetitions
     }
 
-    pub fn set_loop_end(&mut self) {
-        self.loop_sample_end = self.get_current_sample_location()
+    // Turns the loop off after its last repetition and carries out the
+    // follow action
+    fn finish_loop(&mut self) {
+        self.is_looping = false;
+        self.loop_iteration = 1;
+        match self.loop_settings.follow_action {
+            FollowAction::Stop => self.paused = true,
+            FollowAction::Continue => {}
+            FollowAction::JumpToBookmark(key) => self.seek_to_bookmark(key),
+        }
     }
 
-    pub fn get_loop_start_seconds(&mut self) -> f32 {
-        self.get_seconds_for_sample(self.loop_sample_start)
+    fn loop_fade_frames(&self, start: u64, end: u64) -> u64 {
+        ((self.loop_crossfade_seconds * self.sample_rate as f32) as u64).min((end - start) / 2)
     }
 
-    pub fn toggle_loop(&mut self) {
-        self.is_looping = !self.is_looping;
+    // Goes back to the loop start, through the gap and count-in if the loop
+    // has them
+    fn restart_loop(&mut self) -> Result<(), String> {
+        self.complete_loop_repetition();
+        self.seek(self.frame_for_time(self.loop_start))?;
+        if !self.loop_settings.has_interlude() {
+            return Ok(());
+        }
+
+        // Beats follow the tempo of the version playing, and seconds are
+        // real time once the live tempo is applied
+        let beat_frames =
+            60.0 / self.loop_settings.bpm * self.sample_rate as f32 * self.current_speed.speed;
+        let second_frames = self.sample_rate as f64 * self.live_tempo;
+        self.interlude = Some(Interlude::new(
+            &self.loop_settings,
+            beat_frames as u64,
+            second_frames as u64,
+            self.sample_rate,
+        ));
+        // The tail still buffered there belongs before the interlude
+        self.pan_extract.reset();
+
+        if let Some((start, end)) = self.loop_bounds() {
+            let fade = self.loop_fade_frames(start, end);
+            self.loop_fade_in = (fade > 0).then_some((0, fade));
+        }
+        Ok(())
     }
 
-    pub fn get_current_time_seconds(&mut self) -> f32 {
-        let current = self.get_current_sample_location();
-        self.get_seconds_for_sample(current)
+    fn fade_in_after_interlude(&mut self, frame: &mut [f32]) {
+        let Some((done, fade)) = self.loop_fade_in else {
+            return;
+        };
+        let t = (done as f32 + 0.5) / fade as f32 * std::f32::consts::FRAC_PI_2;
+        for sample in frame.iter_mut() {
+            *sample *= t.sin();
+        }
+        self.loop_fade_in = (done + 1 < fade).then_some((done + 1, fade));
     }
 
-    pub fn seek_forwards(&mut self, seconds: usize) {
-        let bytes_to_seek =
-            (self.sample_rate * seconds * self.channels) as f32 * self.current_speed.speed;
-        self.file
-            .seek(SeekFrom::Current(bytes_to_seek as i64))
-            .expect("Could not seek forwards");
+    // Over the last frames before the loop end, fades in the frames from the
+    // loop start, then carries on from just after them. Loops with a gap or
+    // count-in fade out instead, and back in once it is over.
+    fn crossfade_loop_seam(&mut self, frame: &mut [f32]) -> Result<(), String> {
+        let Some((start, end)) = self.loop_frames() else {
+            return Ok(());
+        };
+        let fade = self.loop_fade_frames(start, end);
+        // The frame just read sits one before the current position
+        let index = self.decoder.position().saturating_sub(1);
+        if fade == 0 || index < end - fade || index >= end {
+            return Ok(());
+        }
+
+        let k = index - (end - fade);
+        let t = (k as f32 + 0.5) / fade as f32 * std::f32::consts::FRAC_PI_2;
+
+        if self.loop_settings.has_interlude() {
+            for sample in frame.iter_mut() {
+                *sample *= t.cos();
+            }
+            if k + 1 == fade {
+                self.restart_loop()?;
+            }
+            return Ok(());
+        }
+
+        if self.loop_head.is_empty() {
+            let resume = self.decoder.position();
+            self.seek(start)?;
+            let mut head = vec![0.0; fade as usize * self.channels];
+            for head_frame in head.chunks_mut(self.channels) {
+                self.read_source_frame(head_frame);
+            }
+            self.seek(resume)?;
+            self.loop_head = head;
+        }
+
+        // Equal power curves, as the two ends are not usually correlated
+        let head = &self.loop_head[k as usize * self.channels..][..self.channels];
+        for (sample, head_sample) in frame.iter_mut().zip(head) {
+            *sample = *sample * t.cos() + head_sample * t.sin();
+        }
+
+        if k + 1 == fade {
+            self.complete_loop_repetition();
+            self.seek(start + fade)?;
+        }
+        Ok(())
     }
 
-    pub fn seek_to_sample(&mut self, sample: f32) {
-        let byte_position = (sample * self.bytes_per_sample as f32) as u64 + 44; // Add header size
-        self.file
-            .seek(SeekFrom::Start(byte_position))
-            .expect("Could not seek to sample");
+    fn complete_loop_repetition(&mut self) {
+        self.loop_iteration += 1;
+        let (_, fastest) = self.trainer_tempo_range();
+        let Some(trainer) = &mut self.trainer else {
+            return;
+        };
+        if trainer.complete_repetition(&mut self.loop_settings.trainer, fastest) {
+            self.apply_trainer_tempo();
+            self.save_loop();
+        }
     }
 
-    pub fn seek_backwards(&mut self, seconds: usize) {
-        let bytes_to_seek =
-            (self.sample_rate * seconds * self.channels) as f32 * self.current_speed.speed;
+    // Trainer tempos, relative to the original, that the live tempo can
+    // reach from the version playing
+    fn trainer_tempo_range(&self) -> (f64, f64) {
+        let speed = self.current_speed.speed as f64;
+        (MIN_LIVE_TEMPO / speed, MAX_LIVE_TEMPO / speed)
+    }
 
-        if self.get_current_byte_location() < bytes_to_seek as usize {
-            self.file
-                .seek(SeekFrom::Start(44))
-                .expect("Could not seek to start");
-            return;
+    // The trainer tempo is relative to the original recording, so the live
+    // tempo makes up the difference from the version playing. On a
+    // pre-rendered version of the same tempo it stays at 100%. Tempos the
+    // version cannot reach are pulled in, so the trainer shows what is heard.
+    fn apply_trainer_tempo(&mut self) {
+        let (slowest, fastest) = self.trainer_tempo_range();
+        if let Some(trainer) = &mut self.trainer {
+            trainer.tempo = trainer.tempo.clamp(slowest, fastest);
+            let tempo = trainer.tempo * self.current_speed.speed as f64;
+            self.live_tempo = tempo.clamp(MIN_LIVE_TEMPO, MAX_LIVE_TEMPO);
         }
+    }
 
-        self.file
-            .seek(SeekFrom::Current(-(bytes_to_seek as i64)))
-            .expect("Could not seek backwards");
+    /// Starts the speed trainer on the loop, or stops it and goes back to the
+    /// live tempo from before.
+    pub fn toggle_trainer(&mut self) {
+        match self.trainer.take() {
+            Some(trainer) => self.live_tempo = trainer.previous_live_tempo,
+            None => {
+                self.trainer = Some(Trainer::new(&self.loop_settings.trainer, self.live_tempo));
+                self.is_looping = true;
+                self.loop_iteration = 1;
+                self.apply_trainer_tempo();
+            }
+        }
     }
 
-    pub fn set_next_fastest_speed(&mut self) {
-        match self.get_next_fastest_speed() {
-            Some(speed) => {
-                self.set_speed(speed.speed).unwrap();
+    pub fn mark_repetition_missed(&mut self) {
+        if let Some(trainer) = &mut self.trainer {
+            trainer.mark_missed();
+        }
+    }
+
+    pub fn change_trainer_start(&mut self, delta: f64) {
+        let settings = &mut self.loop_settings.trainer;
+        settings.start_tempo = Self::round_tempo(settings.start_tempo + delta)
+            .clamp(MIN_LIVE_TEMPO, settings.target_tempo);
+        self.save_loop();
+    }
+
+    pub fn change_trainer_target(&mut self, delta: f64) {
+        let settings = &mut self.loop_settings.trainer;
+        settings.target_tempo = Self::round_tempo(settings.target_tempo + delta)
+            .clamp(settings.start_tempo, MAX_LIVE_TEMPO);
+        self.save_loop();
+    }
+
+    pub fn change_trainer_step(&mut self, delta: f64) {
+        let settings = &mut self.loop_settings.trainer;
+        settings.step = Self::round_tempo(settings.step + delta).clamp(0.01, 0.5);
+        self.save_loop();
+    }
+
+    pub fn change_trainer_repetitions(&mut self, delta: i32) {
+        let settings = &mut self.loop_settings.trainer;
+        settings.repetitions = settings.repetitions.saturating_add_signed(delta).max(1);
+        self.save_loop();
+    }
+
+    // Whole percent, so repeated steps land on exact values
+    fn round_tempo(tempo: f64) -> f64 {
+        (tempo * 100.0).round() / 100.0
+    }
+
+    /// Lengthens or shortens the gap by half seconds or whole beats.
+    pub fn change_loop_gap(&mut self, steps: i32) {
+        let settings = &mut self.loop_settings;
+        let step = match settings.gap_unit {
+            GapUnit::Seconds => 0.5,
+            GapUnit::Beats => 1.0,
+        };
+        settings.gap = (settings.gap + steps as f32 * step).max(0.0);
+        self.save_loop();
+    }
+
+    pub fn toggle_loop_gap_unit(&mut self) {
+        let settings = &mut self.loop_settings;
+        settings.gap_unit = match settings.gap_unit {
+            GapUnit::Seconds => GapUnit::Beats,
+            GapUnit::Beats => GapUnit::Seconds,
+        };
+        settings.gap = settings.gap.round();
+        self.save_loop();
+    }
+
+    pub fn change_loop_count_in(&mut self, delta: i32) {
+        let settings = &mut self.loop_settings;
+        settings.count_in_beats = settings.count_in_beats.saturating_add_signed(delta);
+        self.save_loop();
+    }
+
+    pub fn change_loop_bpm(&mut self, delta: f32) {
+        let settings = &mut self.loop_settings;
+        settings.bpm = (settings.bpm + delta).clamp(20.0, 400.0);
+        self.save_loop();
+    }
+
+    pub fn change_loop_repetitions(&mut self, delta: i32) {
+        let settings = &mut self.loop_settings;
+        settings.repetitions = settings.repetitions.saturating_add_signed(delta);
+        self.save_loop();
+    }
+
+    pub fn next_loop_follow_action(&mut self) {
+        let settings = &mut self.loop_settings;
+        settings.follow_action = settings.follow_action.next();
+        self.save_loop();
+    }
+
+    pub fn set_loop_crossfade(&mut self, seconds: f32) {
+        self.loop_crossfade_seconds = seconds;
+    }
+
+    pub fn set_snap_loop_points(&mut self, snap: bool) {
+        self.snap_loop_points = snap;
+    }
+
+    // Moves a new loop point to the nearest place the waveform crosses zero,
+    // so the cut at the loop seam is as quiet as possible. Only the decoder is
+    // moved to look, so a speed crossfade or read ahead loop start carries on.
+    fn snap_to_zero_crossing(&mut self, frame: u64) -> Result<u64, String> {
+        let search = (ZERO_CROSSING_SEARCH_SECONDS * self.sample_rate as f32) as u64;
+        let from = frame.saturating_sub(search);
+        let resume = self.decoder.position();
+        self.decoder.seek(from)?;
+
+        let mut previous = None;
+        let mut best: Option<u64> = None;
+        let mut buffer = vec![0.0; self.channels];
+        for candidate in from..frame + search {
+            if !self.decoder.read_frame(&mut buffer) {
+                break;
+            }
+            let mono: f32 = buffer.iter().sum();
+            if let Some(previous) = previous
+                && (previous < 0.0) != (mono < 0.0)
+                && best.is_none_or(|best| candidate.abs_diff(frame) < best.abs_diff(frame))
+            {
+                best = Some(candidate);
             }
-            None => {}
+            previous = Some(mono);
         }
+
+        self.decoder.seek(resume)?;
+        Ok(best.unwrap_or(frame))
     }
 
-    pub fn set_next_slowest_speed(&mut self) {
-        match self.get_next_slowest_speed() {
-            Some(speed) => {
-                self.set_speed(speed.speed).unwrap();
+    fn mix_layers(&mut self, frame: &mut [f32]) {
+        // Layers are only separated from the original, so other speed
+        // versions always play the full mix
+        if self.layers.is_empty() || self.current_speed.speed != 1.0 {
+            return;
+        }
+
+        let step = 1.0 / (LAYER_CROSSFADE_SECONDS * self.sample_rate as f32);
+        let full_mix_target = if self.selected_layer.is_none() {
+            1.0
+        } else {
+            0.0
+        };
+        self.full_mix_gain = fade_towards(self.full_mix_gain, full_mix_target, step);
+        for sample in frame.iter_mut() {
+            *sample *= self.full_mix_gain;
+        }
+
+        for source in self.layers.iter_mut() {
+            // Always read so the layer stays in step with the mix
+            if !source.decoder.read_frame(&mut self.layer_frame) {
+                self.layer_frame.fill(0.0);
             }
-            None => {
-                // No next slowest speed available
+            let target = if self.selected_layer == Some(source.layer) {
+                1.0
+            } else {
+                0.0
+            };
+            source.gain = fade_towards(source.gain, target, step);
+            for (sample, layer_sample) in frame.iter_mut().zip(&self.layer_frame) {
+                *sample += layer_sample * source.gain;
             }
         }
     }
 
-    pub fn set_speed(&mut self, speed: f32) -> Result<(), String> {
-        // Pause playback before switching
-        let was_playing = !self.paused;
+    pub fn next_layer(&mut self) {
+        if self.layers.is_empty() {
+            return;
+        }
+        // Full mix, then each layer that opened
+        let index = self
+            .selected_layer
+            .and_then(|layer| self.layers.iter().position(|s| s.layer == layer));
+        let next = match index {
+            None => self.layers.first(),
+            Some(index) => self.layers.get(index + 1),
+        };
+        self.selected_layer = next.map(|source| source.layer);
+    }
+
+    fn layer_name(&self) -> String {
+        if self.layers.is_empty() {
+            return "full mix (not separated)".to_string();
+        }
+        let name = match self.selected_layer {
+            None => "full mix",
+            Some(Layer::Harmonic) => "harmonic",
+            Some(Layer::Percussive) => "percussive",
+        };
+        if self.current_speed.speed != 1.0 && self.selected_layer.is_some() {
+            format!("{} (original speed only)", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    // Moves the playhead of the song and of its separated layers together
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.decoder.seek(frame)?;
+        self.loop_head.clear();
+        self.finish_version_fade();
+        self.sync_layers()
+    }
+
+    // A seek asked for by the user, which also drops audio still buffered
+    // from before it
+    fn jump(&mut self, frame: u64) -> Result<(), String> {
+        self.pan_extract.reset();
+        self.seek(frame)
+    }
+
+    fn sync_layers(&mut self) -> Result<(), String> {
+        self.seek_layers(self.current_speed.speed, self.decoder.position())
+    }
+
+    // Layers only follow the original, so at other speeds they are left alone
+    fn seek_layers(&mut self, speed: f32, position: u64) -> Result<(), String> {
+        if speed != 1.0 {
+            return Ok(());
+        }
+        for source in self.layers.iter_mut() {
+            source.decoder.seek(position)?;
+        }
+        Ok(())
+    }
+
+    // Positions are kept in seconds of the original recording, so they stay
+    // in place whichever speed version is playing
+    fn frame_for_time(&self, time: f64) -> u64 {
+        self.frame_for_time_in(time, &self.current_speed)
+    }
+
+    fn frame_for_time_in(&self, time: f64, version: &SpeedVersion) -> u64 {
+        (version.version_time(time).max(0.0) * self.sample_rate as f64).round() as u64
+    }
+
+    fn time_for_frame(&self, frame: u64) -> f64 {
+        let time = frame as f64 / self.sample_rate as f64;
+        self.current_speed.original_time(time).max(0.0)
+    }
+
+    pub fn current_time(&self) -> f64 {
+        self.time_for_frame(self.decoder.position())
+    }
+
+    fn loop_point_here(&mut self) -> Result<f64, String> {
+        let mut frame = self.decoder.position();
+        if self.snap_loop_points {
+            frame = self.snap_to_zero_crossing(frame)?;
+        }
+        Ok(self.time_for_frame(frame))
+    }
+
+    // A failed snap may leave the decoder inside its search window
+    pub fn set_loop_start(&mut self) {
+        match self.loop_point_here() {
+            Ok(time) => {
+                self.loop_start = time;
+                self.loop_iteration = 1;
+                self.save_loop();
+            }
+            Err(e) => self.stop_with_error(e),
+        }
+    }
+
+    pub fn set_loop_end(&mut self) {
+        match self.loop_point_here() {
+            Ok(time) => {
+                self.loop_end = time;
+                self.loop_iteration = 1;
+                self.save_loop();
+            }
+            Err(e) => self.stop_with_error(e),
+        }
+    }
+
+    pub fn toggle_loop(&mut self) {
+        self.is_looping = !self.is_looping;
+        self.loop_iteration = 1;
+    }
+
+    fn frames_for_seconds(&self, seconds: usize) -> u64 {
+        ((self.sample_rate * seconds) as f32 * self.current_speed.speed) as u64
+    }
+
+    pub fn seek_forwards(&mut self, seconds: usize) {
+        let target = self.decoder.position() + self.frames_for_seconds(seconds);
+        let result = self.jump(target);
+        self.report(result);
+    }
+
+    pub fn seek_backwards(&mut self, seconds: usize) {
+        let target = self
+            .decoder
+            .position()
+            .saturating_sub(self.frames_for_seconds(seconds));
+        let result = self.jump(target);
+        self.report(result);
+    }
+
+    pub fn set_next_fastest_speed(&mut self) {
+        if let Some(version) = self.get_next_fastest_speed() {
+            let result = self.set_speed(version.speed);
+            self.report(result);
+        }
+    }
+
+    pub fn set_next_slowest_speed(&mut self) {
+        if let Some(version) = self.get_next_slowest_speed() {
+            let result = self.set_speed(version.speed);
+            self.report(result);
+        }
+    }
+
+    // Keeps the outcome of an action for the TUI, as this runs on the engine
+    // thread where a panic would silently stop the audio
+    fn report(&mut self, result: Result<(), String>) {
+        self.error = result.err();
+    }
+
+    // Playback can't carry on from an unknown position, so it is paused
+    fn stop_with_error(&mut self, error: String) {
+        self.error = Some(error);
         self.paused = true;
+    }
 
-        // Get the current time position before switching
-        let current_time = self.get_current_time_seconds();
+    /// Tempo for the engine's time stretch stage.
+    pub fn stretch_tempo(&self) -> f64 {
+        // Transposing plays the stretched audio faster or slower by the pitch
+        // ratio, so the stretch makes up for it in length
+        let tempo = if self.varispeed { 1.0 } else { self.live_tempo };
+        tempo / self.transpose.ratio()
+    }
 
-        // Check if we have this speed version
-        if let Some(version) = self
-            .song_data
-            .speed_versions
+    /// Rate at which the engine plays back the stretched audio, where 2.0
+    /// is twice as fast and an octave up.
+    pub fn resample_ratio(&self) -> f64 {
+        let ratio = self.transpose.ratio();
+        if self.varispeed {
+            ratio * self.live_tempo
+        } else {
+            ratio
+        }
+    }
+
+    pub fn toggle_pan_extract(&mut self) {
+        self.pan_extract.toggle();
+    }
+
+    pub fn move_pan_extract(&mut self, delta: f32) {
+        self.pan_extract.move_pan(delta);
+    }
+
+    pub fn change_pan_extract_width(&mut self, delta: f32) {
+        self.pan_extract.change_width(delta);
+    }
+
+    pub fn next_channel_mode(&mut self) {
+        self.channel_matrix.next_mode();
+    }
+
+    pub fn next_equalizer_preset(&mut self) {
+        self.equalizer.next_preset();
+        self.save_equalizer_settings();
+    }
+
+    pub fn toggle_varispeed(&mut self) {
+        self.varispeed = !self.varispeed;
+    }
+
+    pub fn change_live_tempo(&mut self, delta: f64) {
+        self.live_tempo =
+            Self::round_tempo(self.live_tempo + delta).clamp(MIN_LIVE_TEMPO, MAX_LIVE_TEMPO);
+    }
+
+    pub fn transpose(&mut self, cents: i32) {
+        self.transpose.shift(cents);
+        self.save_transpose();
+    }
+
+    /// Switches to another speed version at the same point in the song,
+    /// fading from one into the other.
+    pub fn set_speed(&mut self, speed: f32) -> Result<(), String> {
+        let versions = &self.song_data.speed_versions;
+        let Some(index) = versions.iter().position(|v| v.speed == speed) else {
+            return Err(
+                "Speed version not available. Please process it first in Process Speed mode."
+                    .to_string(),
+            );
+        };
+        let current_index = versions
             .iter()
-            .find(|v| v.speed == speed)
+            .position(|v| v.speed == self.current_speed.speed);
+        let version = versions[index].clone();
+
+        // A fade left over from the last switch is cut short
+        self.finish_version_fade();
+        let mut decoder = match self.standby[index].take() {
+            Some(decoder) => decoder,
+            None => Self::open_decoder(&version.file_path, self.preload)?,
+        };
+
+        // Versions can come out a little shorter than the speed suggests, so
+        // a position past the end lands on the last frame
+        let frame = self
+            .frame_for_time_in(self.current_time(), &version)
+            .min(decoder.duration().saturating_sub(1));
+        if let Err(e) = decoder
+            .seek(frame)
+            .and_then(|_| self.seek_layers(version.speed, frame))
         {
-            self.current_speed = version.clone();
+            self.standby[index] = Some(decoder);
+            return Err(e);
+        }
+
+        let previous = std::mem::replace(&mut self.decoder, decoder);
+        self.current_speed = version;
+        if let Some(current_index) = current_index {
+            self.outgoing = Some(VersionFade {
+                index: current_index,
+                decoder: previous,
+                done: 0,
+                frame: vec![0.0; self.channels],
+            });
+            if self.paused {
+                self.finish_version_fade();
+            }
+        }
+
+        // The loop start read ahead for the crossfade came from the old
+        // version
+        self.loop_head.clear();
+        self.apply_trainer_tempo();
+        Ok(())
+    }
+
+    // The two versions hold the same music at the same point, so their
+    // gains sum to one rather than keeping the power constant
+    fn fade_from_previous_version(&mut self, frame: &mut [f32]) {
+        let Some(outgoing) = self.outgoing.as_mut() else {
+            return;
+        };
+        let fade = (SPEED_CROSSFADE_SECONDS * self.sample_rate as f32) as u64;
+        if !outgoing.decoder.read_frame(&mut outgoing.frame) {
+            outgoing.frame.fill(0.0);
+        }
+
+        let t = (outgoing.done as f32 + 0.5) / fade as f32 * std::f32::consts::FRAC_PI_2;
+        let (old_gain, new_gain) = (t.cos().powi(2), t.sin().powi(2));
+        for (sample, old_sample) in frame.iter_mut().zip(&outgoing.frame) {
+            *sample = *sample * new_gain + old_sample * old_gain;
+        }
+
+        outgoing.done += 1;
+        if outgoing.done >= fade {
+            self.finish_version_fade();
+        }
+    }
+
+    // Puts the version faded out back on standby
+    fn finish_version_fade(&mut self) {
+        if let Some(outgoing) = self.outgoing.take() {
+            self.standby[outgoing.index] = Some(outgoing.decoder);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::looping::TrainerSettings;
+    use crate::save_data::LayerVersion;
+    use crate::time_stretch::TimeStretch;
+    use std::path::PathBuf;
+
+    const RATE: usize = 1000;
+    // Ramp samples are frame numbers over this
+    const RAMP_FRAMES: f32 = 100_000.0;
+
+    fn write_wav(path: &Path, seconds: usize) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for i in 0..seconds * RATE {
+            writer.write_sample((i as f32 * 0.01).sin()).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    // A ten second song with a version at half speed, in a song directory of
+    // its own
+    fn song_with_half_speed(name: &str) -> SongData {
+        let song_dir = std::env::temp_dir().join(format!("transcriber_test_{}", name));
+        fs::remove_dir_all(&song_dir).ok();
+        fs::create_dir_all(&song_dir).unwrap();
+        let original = song_dir.join("original.wav");
+        let slow = song_dir.join("speed_2.00.wav");
+        write_wav(&original, 10);
+        write_wav(&slow, 20);
+
+        SongData {
+            original_file_path: original.clone(),
+            hash: name.to_string(),
+            song_dir,
+            speed_versions: vec![version(1.0, original), version(2.0, slow)],
+            layer_versions: Vec::new(),
+        }
+    }
+
+    fn stream_with_half_speed(name: &str) -> AudioStream {
+        AudioStream::new(song_with_half_speed(name), true)
+    }
+
+    fn version(speed: f32, file_path: PathBuf) -> SpeedVersion {
+        SpeedVersion {
+            speed,
+            file_path,
+            time_map: Vec::new(),
+        }
+    }
+
+    fn assert_near(time: f64, expected: f64) {
+        assert!(
+            (time - expected).abs() <= 1.0 / RATE as f64,
+            "{} is not {}",
+            time,
+            expected
+        );
+    }
+
+    // A song whose samples count up, so each one tells which frame it is
+    fn ramp_stream(name: &str) -> AudioStream {
+        let mut song = song_with_half_speed(name);
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&song.original_file_path, spec).unwrap();
+        for i in 0..10 * RATE {
+            writer.write_sample(i as f32 / RAMP_FRAMES).unwrap();
+        }
+        writer.finalize().unwrap();
+        song.speed_versions.truncate(1);
+        AudioStream::new(song, true)
+    }
+
+    fn ramp_frame(sample: f32) -> u64 {
+        (sample * RAMP_FRAMES).round() as u64
+    }
+
+    fn set_loop(stream: &mut AudioStream, start: u64, end: u64) {
+        stream.seek(start).unwrap();
+        stream.set_loop_start();
+        stream.seek(end).unwrap();
+        stream.set_loop_end();
+        stream.toggle_loop();
+        stream.seek(start).unwrap();
+    }
+
+    // Plays until the loop is left or `frames` have been read, returning
+    // where the decoder had got to before each jump back to the loop start
+    fn play_loop(stream: &mut AudioStream, frames: usize) -> Vec<u64> {
+        let mut turns = Vec::new();
+        let mut last = stream.decoder.position();
+        for _ in 0..frames {
+            stream.read_frame();
+            let position = stream.decoder.position();
+            if position < last {
+                turns.push(last);
+            }
+            last = position;
+            if stream.paused || !stream.is_looping {
+                break;
+            }
+        }
+        turns
+    }
 
-            // Open the new file and get its metadata
-            let file = File::open(&version.file_path).expect("Could not open speed version file");
-            let file_size = file.metadata().expect("Could not get file metadata").len();
+    #[test]
+    fn loop_end_is_not_played() {
+        // Without a crossfade the frame before the loop end is the last heard
+        let mut stream = ramp_stream("loop_end");
+        stream.set_loop_crossfade(0.0);
+        set_loop(&mut stream, 1000, 1500);
+        let played: Vec<u64> = (0..1001)
+            .map(|_| ramp_frame(stream.read_frame()[0]))
+            .collect();
+        assert_eq!(played[499], 1499);
+        assert_eq!(played[500], 1000);
+        assert_eq!(played[999], 1499);
+        assert_eq!(played[1000], 1000);
 
-            // Create a new reader
-            let mut reader = BufReader::new(file);
+        // With one, that frame is faded out under the loop start, and play
+        // carries on from after the faded in frames
+        let mut stream = ramp_stream("loop_end_crossfade");
+        stream.set_loop_crossfade(0.05);
+        set_loop(&mut stream, 1000, 1500);
+        let fade = 50;
+        let t = (fade as f32 - 0.5) / fade as f32 * std::f32::consts::FRAC_PI_2;
+        let seam = (1499.0 * t.cos() + 1049.0 * t.sin()) / RAMP_FRAMES;
+        let played: Vec<f32> = (0..951).map(|_| stream.read_frame()[0]).collect();
+        for turn in [499, 949] {
+            assert!((played[turn] - seam).abs() < 1e-6, "{}", played[turn]);
+            assert_eq!(ramp_frame(played[turn + 1]), 1050);
+        }
+    }
 
-            // Seek past the WAV header
-            reader
-                .seek(SeekFrom::Start(44))
-                .expect("Could not seek past header");
+    #[test]
+    fn follow_action_comes_after_the_last_repetition() {
+        for follow_action in [
+            FollowAction::Stop,
+            FollowAction::Continue,
+            FollowAction::JumpToBookmark(1),
+        ] {
+            let mut stream = ramp_stream("follow_action");
+            stream.seek(5000).unwrap();
+            stream.set_bookmark(1);
+            stream.set_loop_crossfade(0.0);
+            set_loop(&mut stream, 1000, 1100);
+            stream.loop_settings.repetitions = 3;
+            stream.loop_settings.follow_action = follow_action;
 
-            // Calculate the new position
-            let (byte_position, _) = self.calculate_position_for_time(current_time, speed);
+            // Two jumps back mean three passes through the loop
+            let turns = play_loop(&mut stream, 1000);
+            let describe = follow_action.describe();
+            assert_eq!(turns, vec![1100, 1100], "{}", describe);
+            assert!(!stream.is_looping, "{}", describe);
 
-            // Verify the position is valid
-            if byte_position >= file_size {
-                return Err("Invalid position after speed change".to_string());
+            let position = stream.decoder.position();
+            match follow_action {
+                FollowAction::Stop => {
+                    assert!(stream.paused);
+                    assert_eq!(position, 1100);
+                }
+                // The frame the loop would have jumped back at is played
+                FollowAction::Continue => {
+                    assert!(!stream.paused);
+                    assert_eq!(position, 1101);
+                }
+                FollowAction::JumpToBookmark(_) => {
+                    assert!(!stream.paused);
+                    assert_eq!(position, 5001);
+                }
             }
+        }
+    }
 
-            // Seek to the aligned position
-            reader
-                .seek(SeekFrom::Start(byte_position))
-                .expect("Could not seek to position");
+    #[test]
+    fn beat_gap_follows_the_live_tempo() {
+        for live_tempo in [1.0, 0.5, 1.25] {
+            let mut stream = ramp_stream("beat_gap");
+            stream.set_loop_crossfade(0.0);
+            stream.loop_settings.gap = 4.0;
+            stream.loop_settings.gap_unit = GapUnit::Beats;
+            stream.loop_settings.bpm = 120.0;
+            stream.live_tempo = live_tempo;
+            set_loop(&mut stream, 1000, 1100);
+            stream.seek(1100).unwrap();
 
-            // Read and discard a few frames to ensure clean buffer state
-            let mut buffer = vec![0u8; self.channels * self.bytes_per_sample * 4];
-            reader.read_exact(&mut buffer).ok();
+            // Played through the engine's time stretch, as it is heard
+            let mut stretch = TimeStretch::new(1, RATE);
+            stretch.set_tempo(stream.stretch_tempo());
+            let mut frame = [0.0];
+            let mut heard = 0;
+            loop {
+                stretch.next_frame(&mut frame, &mut |source| {
+                    source.copy_from_slice(&stream.read_frame())
+                });
+                heard += 1;
+                if stream.interlude.is_none() {
+                    break;
+                }
+            }
 
-            self.file = reader;
+            // Four beats at 120 bpm take two seconds at the original tempo.
+            // The stretch reads up to a window and its search range ahead.
+            let expected = 2.0 * RATE as f64 / live_tempo;
+            assert!(
+                (heard as f64 - expected).abs() <= 0.06 * RATE as f64 / live_tempo,
+                "tempo {} gap of {} frames",
+                live_tempo,
+                heard
+            );
+        }
+    }
 
-            // Restore playback state
-            self.paused = !was_playing;
-            return Ok(());
+    #[test]
+    fn trainer_stops_where_the_version_tops_out() {
+        let mut stream = stream_with_half_speed("trainer");
+        stream.set_speed(2.0).unwrap();
+        stream.loop_settings.trainer = TrainerSettings {
+            start_tempo: 0.6,
+            step: 0.1,
+            target_tempo: 1.0,
+            repetitions: 1,
+            reached_tempo: None,
+        };
+        stream.toggle_trainer();
+        for _ in 0..10 {
+            stream.complete_loop_repetition();
         }
 
-        Err(
-            "Speed version not available. Please process it first in Process Speed mode."
-                .to_string(),
-        )
+        // At half speed the live tempo's 150% is 75% of the original
+        let trainer = stream.trainer.as_ref().unwrap();
+        assert_near(trainer.tempo, 0.75);
+        assert_near(stream.live_tempo, MAX_LIVE_TEMPO);
+        assert_near(stream.loop_settings.trainer.reached_tempo.unwrap(), 0.75);
+        assert!(
+            stream
+                .output_data()
+                .trainer
+                .contains("this version tops out at 75%")
+        );
+    }
+
+    #[test]
+    fn position_survives_speed_switch() {
+        let mut stream = stream_with_half_speed("position");
+        stream.seek(3 * RATE as u64).unwrap();
+        assert_near(stream.current_time(), 3.0);
+
+        stream.set_speed(2.0).unwrap();
+        assert_eq!(stream.decoder.position(), 6 * RATE as u64);
+        assert_near(stream.current_time(), 3.0);
+
+        stream.set_speed(1.0).unwrap();
+        assert_eq!(stream.decoder.position(), 3 * RATE as u64);
+    }
+
+    #[test]
+    fn loop_stays_on_the_same_seconds() {
+        let mut stream = stream_with_half_speed("loop");
+        stream.seek(RATE as u64).unwrap();
+        stream.set_loop_start();
+        stream.seek(2 * RATE as u64).unwrap();
+        stream.set_loop_end();
+        stream.toggle_loop();
+
+        stream.set_speed(2.0).unwrap();
+        assert_near(stream.loop_start, 1.0);
+        assert_near(stream.loop_end, 2.0);
+        assert_eq!(
+            stream.loop_bounds(),
+            Some((2 * RATE as u64, 4 * RATE as u64))
+        );
+    }
+
+    #[test]
+    fn bookmark_jump_lands_on_the_same_second() {
+        let mut stream = stream_with_half_speed("bookmark");
+        stream.seek(4 * RATE as u64).unwrap();
+        stream.set_bookmark(1);
+        stream.seek(0).unwrap();
+
+        stream.set_speed(2.0).unwrap();
+        stream.seek_to_bookmark(1);
+        assert_eq!(stream.decoder.position(), 8 * RATE as u64);
+        assert_near(stream.current_time(), 4.0);
+
+        stream.set_speed(1.0).unwrap();
+        stream.seek(0).unwrap();
+        stream.seek_to_bookmark(1);
+        assert_eq!(stream.decoder.position(), 4 * RATE as u64);
+    }
+
+    #[test]
+    fn broken_version_fails_only_its_switch() {
+        let song_data = song_with_half_speed("broken");
+        fs::write(&song_data.speed_versions[1].file_path, b"not audio").unwrap();
+        let mut stream = AudioStream::new(song_data, true);
+        stream.seek(3 * RATE as u64).unwrap();
+
+        stream.set_next_slowest_speed();
+        assert!(stream.error.is_some());
+        assert_eq!(stream.current_speed.speed, 1.0);
+        assert_eq!(stream.decoder.position(), 3 * RATE as u64);
+        assert_eq!(stream.read_frame().len(), 1);
+    }
+
+    #[test]
+    fn snapping_a_loop_point_keeps_the_speed_crossfade() {
+        let mut stream = stream_with_half_speed("snap");
+        stream.set_snap_loop_points(true);
+        stream.seek(3 * RATE as u64).unwrap();
+        stream.set_speed(2.0).unwrap();
+        stream.read_frame();
+        let position = stream.decoder.position();
+
+        stream.set_loop_start();
+        assert!(stream.outgoing.is_some());
+        assert_eq!(stream.decoder.position(), position);
+        assert!(stream.error.is_none());
+    }
+
+    #[test]
+    fn broken_layer_is_left_out() {
+        let mut song_data = song_with_half_speed("layers");
+        let harmonic = song_data.song_dir.join("harmonic.wav");
+        let percussive = song_data.song_dir.join("percussive.wav");
+        write_wav(&harmonic, 10);
+        fs::write(&percussive, b"not audio").unwrap();
+        song_data.layer_versions = vec![
+            LayerVersion {
+                layer: Layer::Harmonic,
+                file_path: harmonic,
+            },
+            LayerVersion {
+                layer: Layer::Percussive,
+                file_path: percussive,
+            },
+        ];
+        let mut stream = AudioStream::new(song_data, true);
+        assert!(stream.error.is_some());
+
+        stream.next_layer();
+        assert_eq!(stream.selected_layer, Some(Layer::Harmonic));
+        stream.next_layer();
+        assert_eq!(stream.selected_layer, None);
     }
 }
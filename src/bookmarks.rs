@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub enum BookmarkColor {
+    #[default]
+    Red,
+    Yellow,
+    Green,
+    Cyan,
+    Blue,
+    Magenta,
+}
+
+impl BookmarkColor {
+    pub fn next(self) -> Self {
+        match self {
+            BookmarkColor::Red => BookmarkColor::Yellow,
+            BookmarkColor::Yellow => BookmarkColor::Green,
+            BookmarkColor::Green => BookmarkColor::Cyan,
+            BookmarkColor::Cyan => BookmarkColor::Blue,
+            BookmarkColor::Blue => BookmarkColor::Magenta,
+            BookmarkColor::Magenta => BookmarkColor::Red,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Bookmark {
+    pub label: String,
+    pub color: BookmarkColor,
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Seconds into the original recording
+    pub time: f64,
+    /// Number key that jumps straight to this bookmark
+    #[serde(default)]
+    pub slot: Option<u8>,
+}
+
+// The bookmarks.json written when there were ten fixed bookmarks, one per
+// number key. Unset ones were left at 0.
+#[derive(Deserialize)]
+struct NumberedBookmarks {
+    bookmark_1: f32,
+    bookmark_2: f32,
+    bookmark_3: f32,
+    bookmark_4: f32,
+    bookmark_5: f32,
+    bookmark_6: f32,
+    bookmark_7: f32,
+    bookmark_8: f32,
+    bookmark_9: f32,
+    bookmark_0: f32,
+}
+
+impl NumberedBookmarks {
+    fn into_list(self, samples_per_second: f64) -> Vec<Bookmark> {
+        let numbered = [
+            (1, self.bookmark_1),
+            (2, self.bookmark_2),
+            (3, self.bookmark_3),
+            (4, self.bookmark_4),
+            (5, self.bookmark_5),
+            (6, self.bookmark_6),
+            (7, self.bookmark_7),
+            (8, self.bookmark_8),
+            (9, self.bookmark_9),
+            (0, self.bookmark_0),
+        ];
+        numbered
+            .into_iter()
+            .filter(|(_, sample)| *sample > 0.0)
+            .map(|(key, sample)| Bookmark {
+                label: format!("Bookmark {}", key),
+                color: BookmarkColor::default(),
+                note: None,
+                time: sample as f64 / samples_per_second,
+                slot: Some(key),
+            })
+            .collect()
+    }
+}
+
+/// A song's bookmarks, kept in order of position.
+pub struct Bookmarks {
+    list: Vec<Bookmark>,
+}
+
+impl Bookmarks {
+    /// `samples_per_second` converts the numbered bookmarks saved by older
+    /// versions, which counted interleaved samples of the original recording.
+    pub fn load(path: &Path, samples_per_second: f64) -> Self {
+        let bookmarks_str = fs::read_to_string(path).unwrap_or_default();
+        let mut list = serde_json::from_str::<Vec<Bookmark>>(&bookmarks_str)
+            .ok()
+            .or_else(|| {
+                serde_json::from_str::<NumberedBookmarks>(&bookmarks_str)
+                    .ok()
+                    .map(|numbered| numbered.into_list(samples_per_second))
+            })
+            .unwrap_or_default();
+        list.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Bookmarks { list }
+    }
+
+    pub fn save(&self, path: &Path) {
+        let bookmarks_str = serde_json::to_string_pretty(&self.list).unwrap();
+        fs::write(path, bookmarks_str).expect("Could not write bookmarks");
+    }
+
+    pub fn list(&self) -> &[Bookmark] {
+        &self.list
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Bookmark> {
+        self.list.get_mut(index)
+    }
+
+    pub fn slot(&self, key: u8) -> Option<usize> {
+        self.list.iter().position(|b| b.slot == Some(key))
+    }
+
+    /// Adds a bookmark, or moves the one already on `slot`, and returns
+    /// where it ends up in the list.
+    pub fn set(&mut self, time: f64, slot: Option<u8>) -> usize {
+        let bookmark = match slot.and_then(|key| self.slot(key)) {
+            Some(index) => Bookmark {
+                time,
+                ..self.list.remove(index)
+            },
+            None => Bookmark {
+                label: match slot {
+                    Some(key) => format!("Bookmark {}", key),
+                    None => format!("Bookmark {}", self.list.len() + 1),
+                },
+                color: BookmarkColor::default(),
+                note: None,
+                time,
+                slot,
+            },
+        };
+        let index = self.list.partition_point(|b| b.time <= time);
+        self.list.insert(index, bookmark);
+        index
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.list.len() {
+            self.list.remove(index);
+        }
+    }
+
+    /// The first bookmark after `time`.
+    pub fn next_after(&self, time: f64) -> Option<usize> {
+        let index = self.list.partition_point(|b| b.time <= time);
+        (index < self.list.len()).then_some(index)
+    }
+
+    /// The last bookmark before `time`.
+    pub fn previous_before(&self, time: f64) -> Option<usize> {
+        self.list.partition_point(|b| b.time < time).checked_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &str) -> Bookmarks {
+        let path = std::env::temp_dir().join(format!("transcriber_test_{}.json", name));
+        fs::write(&path, contents).unwrap();
+        let bookmarks = Bookmarks::load(&path, 88200.0);
+        fs::remove_file(&path).ok();
+        bookmarks
+    }
+
+    #[test]
+    fn loads_numbered_bookmarks_as_seconds() {
+        let bookmarks = load(
+            "numbered_bookmarks",
+            r#"{
+                "bookmark_1": 441000.0, "bookmark_2": 0.0, "bookmark_3": 88200.0,
+                "bookmark_4": 0.0, "bookmark_5": 0.0, "bookmark_6": 0.0,
+                "bookmark_7": 0.0, "bookmark_8": 0.0, "bookmark_9": 0.0,
+                "bookmark_0": 176400.0
+            }"#,
+        );
+        let list: Vec<_> = bookmarks.list().iter().map(|b| (b.slot, b.time)).collect();
+        assert_eq!(list, [(Some(3), 1.0), (Some(0), 2.0), (Some(1), 5.0)]);
+    }
+}
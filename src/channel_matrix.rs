@@ -0,0 +1,95 @@
+// Centre cancel keeps the centre below this frequency, where bass and kick sit
+const CENTER_CANCEL_KEEP_HZ: f32 = 200.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChannelMode {
+    Stereo,
+    Left,
+    Right,
+    /// The mono sum, which is also the mid half of a mid/side split
+    Mono,
+    Side,
+    CenterCancel,
+}
+
+impl ChannelMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelMode::Stereo => "stereo",
+            ChannelMode::Left => "left only",
+            ChannelMode::Right => "right only",
+            ChannelMode::Mono => "mono sum (mid)",
+            ChannelMode::Side => "side only",
+            ChannelMode::CenterCancel => "center cancel",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            ChannelMode::Stereo => ChannelMode::Left,
+            ChannelMode::Left => ChannelMode::Right,
+            ChannelMode::Right => ChannelMode::Mono,
+            ChannelMode::Mono => ChannelMode::Side,
+            ChannelMode::Side => ChannelMode::CenterCancel,
+            ChannelMode::CenterCancel => ChannelMode::Stereo,
+        }
+    }
+}
+
+/// Remixes the first two channels of each frame. Files with a single
+/// channel pass through untouched.
+pub struct ChannelMatrix {
+    mode: ChannelMode,
+    // One pole low pass of the mid signal, used by centre cancel
+    low_mid: f32,
+    low_pass_coefficient: f32,
+}
+
+impl ChannelMatrix {
+    pub fn new(sample_rate: usize) -> Self {
+        let low_pass_coefficient =
+            1.0 - (-2.0 * std::f32::consts::PI * CENTER_CANCEL_KEEP_HZ / sample_rate as f32).exp();
+        ChannelMatrix {
+            mode: ChannelMode::Stereo,
+            low_mid: 0.0,
+            low_pass_coefficient,
+        }
+    }
+
+    pub fn mode(&self) -> ChannelMode {
+        self.mode
+    }
+
+    pub fn next_mode(&mut self) {
+        self.mode = self.mode.next();
+        self.low_mid = 0.0;
+    }
+
+    pub fn apply(&mut self, frame: &mut [f32]) {
+        if frame.len() < 2 || self.mode == ChannelMode::Stereo {
+            return;
+        }
+
+        let left = frame[0];
+        let right = frame[1];
+        let mid = (left + right) * 0.5;
+        let side = (left - right) * 0.5;
+
+        let (new_left, new_right) = match self.mode {
+            ChannelMode::Stereo => (left, right),
+            ChannelMode::Left => (left, left),
+            ChannelMode::Right => (right, right),
+            ChannelMode::Mono => (mid, mid),
+            ChannelMode::Side => (side, side),
+            ChannelMode::CenterCancel => {
+                // Whatever is panned dead centre (usually the lead vocal)
+                // cancels out, while the low end is kept so the song
+                // does not lose its bass
+                self.low_mid += (mid - self.low_mid) * self.low_pass_coefficient;
+                (self.low_mid + side, self.low_mid - side)
+            }
+        };
+        frame[0] = new_left;
+        frame[1] = new_right;
+    }
+}
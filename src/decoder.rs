@@ -0,0 +1,54 @@
+use crate::symphonia_decoder::SymphoniaDecoder;
+use crate::wav::WavDecoder;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioSpec {
+    pub channels: usize,
+    pub sample_rate: usize,
+}
+
+/// A source of interleaved f32 frames that can be positioned by frame index.
+///
+/// `AudioStream` only talks to this trait, so new formats and in-memory
+/// sources plug in without touching loop, bookmark or speed handling.
+pub trait Decoder: Send {
+    fn open(path: &Path) -> Result<Self, String>
+    where
+        Self: Sized;
+
+    fn spec(&self) -> AudioSpec;
+
+    /// Length of the stream in frames.
+    fn duration(&self) -> u64;
+
+    /// Index of the next frame `read_frames` will return.
+    fn position(&self) -> u64;
+
+    /// Fills `buffer` with whole interleaved frames and returns how many were
+    /// read. Reading fewer frames than fit in the buffer means the stream ended.
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize;
+
+    /// Moves to `frame`. Seeking past the end parks the decoder at the end.
+    fn seek(&mut self, frame: u64) -> Result<(), String>;
+
+    /// Fills `frame` with the next frame, returning false at the end of the stream.
+    fn read_frame(&mut self, frame: &mut [f32]) -> bool {
+        self.read_frames(frame) == 1
+    }
+}
+
+/// Opens a decoder for `path`, picking the implementation from the file extension.
+pub fn open(path: &Path) -> Result<Box<dyn Decoder>, String> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("flac" | "mp3" | "ogg" | "oga" | "opus") => {
+            Ok(Box::new(SymphoniaDecoder::open(path)?))
+        }
+        _ => Ok(Box::new(WavDecoder::open(path)?)),
+    }
+}
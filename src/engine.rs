@@ -0,0 +1,482 @@
+use crate::audio_stream::{AudioStream, AudioStreamOutputData};
+use crate::limiter::Limiter;
+use crate::resampler::{ResampleQuality, Resampler};
+use crate::time_stretch::TimeStretch;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Frames buffered between the engine and the audio callback. At 44.1kHz this
+// is around 46ms, which is also how far the position display can run ahead.
+const BUFFER_FRAMES: usize = 2048;
+// How long the engine waits for a command before topping the buffer up again
+const FILL_INTERVAL: Duration = Duration::from_millis(2);
+const OUTPUT_DATA_INTERVAL: Duration = Duration::from_millis(20);
+
+pub enum Command {
+    TogglePlay,
+    SeekForwards(usize),
+    SeekBackwards(usize),
+    SetLoopStart,
+    SetLoopEnd,
+    ToggleLoop,
+    CycleLoopSection(i32),
+    ActivateLoopSection,
+    AddLoopSection,
+    DeleteLoopSection,
+    RenameLoopSection(String),
+    ChangeLoopGap(i32),
+    ToggleLoopGapUnit,
+    ChangeLoopCountIn(i32),
+    ChangeLoopBpm(f32),
+    ChangeLoopRepetitions(i32),
+    NextLoopFollowAction,
+    ToggleTrainer,
+    MarkRepetitionMissed,
+    ChangeTrainerStart(f64),
+    ChangeTrainerTarget(f64),
+    ChangeTrainerStep(f64),
+    ChangeTrainerRepetitions(i32),
+    SeekToBookmark(u8),
+    SetBookmark(u8),
+    AddBookmark,
+    NextBookmark,
+    PreviousBookmark,
+    LabelBookmark(String),
+    NoteBookmark(String),
+    NextBookmarkColor,
+    DeleteBookmark,
+    NextFastestSpeed,
+    NextSlowestSpeed,
+    ChangeLiveTempo(f64),
+    ChangeVolume(f32),
+    Transpose(i32),
+    ToggleVarispeed,
+    NextChannelMode,
+    TogglePanExtract,
+    MovePanExtract(f32),
+    ChangePanExtractWidth(f32),
+    NextEqualizerPreset,
+    NextLayer,
+}
+
+#[derive(Clone)]
+pub struct EngineOutputData {
+    pub stream: AudioStreamOutputData,
+    pub output_format: String,
+    pub volume: String,
+}
+
+/// The UI side of the engine: commands go in, display snapshots come out.
+pub struct EngineHandle {
+    commands: Sender<Command>,
+    output_data: Arc<Mutex<EngineOutputData>>,
+    _thread: thread::JoinHandle<()>,
+}
+
+impl EngineHandle {
+    pub fn send(&self, command: Command) {
+        self.commands.send(command).ok();
+    }
+
+    pub fn output_data(&self) -> EngineOutputData {
+        self.output_data.lock().unwrap().clone()
+    }
+}
+
+/// The consuming end of the engine's ring buffer, owned by the audio callback.
+pub struct PlaybackBuffer {
+    consumer: Consumer<f32>,
+    // Raised by the engine when buffered audio is stale (e.g. after a seek)
+    flush: Arc<AtomicBool>,
+    // Rate negotiated with the PipeWire graph, 0 until it is known
+    output_rate: Arc<AtomicU32>,
+    pub channels: usize,
+    pub sample_rate: usize,
+}
+
+impl PlaybackBuffer {
+    /// Tells the engine which rate to resample to once the format is negotiated.
+    pub fn set_output_rate(&self, rate: u32) {
+        self.output_rate.store(rate, Ordering::Release);
+    }
+
+    /// Pops one frame, or writes silence if the engine has fallen behind.
+    /// Safe to call from the real time thread: it never blocks or allocates.
+    pub fn read_frame(&mut self, frame: &mut [f32]) {
+        if self.flush.load(Ordering::Acquire) {
+            let stale = self.consumer.slots();
+            if let Ok(chunk) = self.consumer.read_chunk(stale) {
+                chunk.commit_all();
+            }
+            self.flush.store(false, Ordering::Release);
+        }
+
+        // The engine only pushes whole frames, so checking for a full frame
+        // keeps the channels from drifting out of step on an underrun
+        if self.consumer.slots() < frame.len() {
+            frame.fill(0.0);
+            return;
+        }
+        for sample in frame.iter_mut() {
+            *sample = self.consumer.pop().unwrap_or(0.0);
+        }
+    }
+}
+
+struct Engine {
+    stream: AudioStream,
+    producer: Producer<f32>,
+    flush: Arc<AtomicBool>,
+    commands: Receiver<Command>,
+    output_data: Arc<Mutex<EngineOutputData>>,
+    output_rate: Arc<AtomicU32>,
+    // Graph rate the resampler was last set up for
+    resample_rate: u32,
+    time_stretch: TimeStretch,
+    resampler: Resampler,
+    limiter: Limiter,
+    frame: Vec<f32>,
+}
+
+/// Moves the audio stream onto its own thread, which decodes ahead into a
+/// lock free ring buffer read by the audio callback.
+pub fn start(
+    stream: AudioStream,
+    resample_quality: ResampleQuality,
+) -> (EngineHandle, PlaybackBuffer) {
+    let channels = stream.channels;
+    let sample_rate = stream.sample_rate;
+
+    let (producer, consumer) = RingBuffer::new(BUFFER_FRAMES * channels);
+    let (command_sender, commands) = mpsc::channel();
+    let flush = Arc::new(AtomicBool::new(false));
+    let output_rate = Arc::new(AtomicU32::new(0));
+
+    let mut engine = Engine {
+        stream,
+        producer,
+        flush: flush.clone(),
+        commands,
+        output_data: Arc::new(Mutex::new(EngineOutputData {
+            stream: AudioStreamOutputData::default(),
+            output_format: String::new(),
+            volume: String::new(),
+        })),
+        output_rate: output_rate.clone(),
+        resample_rate: 0,
+        time_stretch: TimeStretch::new(channels, sample_rate),
+        resampler: Resampler::new(channels, resample_quality),
+        limiter: Limiter::new(channels, sample_rate),
+        frame: vec![0.0; channels],
+    };
+    engine.publish_output_data();
+    let output_data = engine.output_data.clone();
+    let thread = thread::spawn(move || engine.run());
+
+    let handle = EngineHandle {
+        commands: command_sender,
+        output_data,
+        _thread: thread,
+    };
+    let buffer = PlaybackBuffer {
+        consumer,
+        flush,
+        output_rate,
+        channels,
+        sample_rate,
+    };
+    (handle, buffer)
+}
+
+impl Engine {
+    fn run(mut self) {
+        let mut last_output = Instant::now();
+        loop {
+            self.fill_buffer();
+
+            if last_output.elapsed() >= OUTPUT_DATA_INTERVAL {
+                self.publish_output_data();
+                last_output = Instant::now();
+            }
+
+            match self.commands.recv_timeout(FILL_INTERVAL) {
+                Ok(command) => self.handle_command(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn publish_output_data(&mut self) {
+        let output_data = EngineOutputData {
+            stream: self.stream.output_data(),
+            output_format: self.output_format(),
+            volume: format!("{:+.0} dB", self.limiter.volume_db()),
+        };
+        *self.output_data.lock().unwrap() = output_data;
+    }
+
+    fn output_format(&self) -> String {
+        let output_rate = self.output_rate.load(Ordering::Acquire) as usize;
+        if output_rate == 0 || output_rate == self.stream.sample_rate {
+            format!("{} Hz", self.stream.sample_rate)
+        } else {
+            format!(
+                "{} Hz -> {} Hz ({} quality)",
+                self.stream.sample_rate,
+                output_rate,
+                self.resampler.quality().name()
+            )
+        }
+    }
+
+    fn update_resampler(&mut self) {
+        let output_rate = self.output_rate.load(Ordering::Acquire);
+        // Until PipeWire reports the graph rate, play at the file's own rate
+        let rate_step = match output_rate {
+            0 => 1.0,
+            rate => self.stream.sample_rate as f64 / rate as f64,
+        };
+        let step = rate_step * self.stream.resample_ratio();
+        if step == self.resampler.step() {
+            return;
+        }
+
+        // Pitch and varispeed changes glide on the fly, but queued audio is stale after a
+        // rate change, and the history is stale when leaving passthrough
+        let passthrough_changed = (step == 1.0) != self.resampler.is_passthrough();
+        self.resampler.set_step(step);
+        if output_rate != self.resample_rate || passthrough_changed {
+            // The limiter runs after the resampler, at the output rate
+            if output_rate != self.resample_rate {
+                self.limiter.set_sample_rate(match output_rate {
+                    0 => self.stream.sample_rate,
+                    rate => rate as usize,
+                });
+            }
+            self.resample_rate = output_rate;
+            self.resampler.reset();
+            self.limiter.reset();
+            self.flush.store(true, Ordering::Release);
+        }
+    }
+
+    fn fill_buffer(&mut self) {
+        self.update_resampler();
+
+        // Wait for the callback to drop stale audio before queueing more
+        if self.flush.load(Ordering::Acquire) {
+            return;
+        }
+
+        self.time_stretch.set_tempo(self.stream.stretch_tempo());
+
+        let channels = self.stream.channels;
+        let stream = &mut self.stream;
+        let time_stretch = &mut self.time_stretch;
+        while self.producer.slots() >= channels {
+            self.resampler.next_frame(&mut self.frame, &mut |input| {
+                time_stretch.next_frame(input, &mut |source| {
+                    source.copy_from_slice(&stream.read_frame())
+                })
+            });
+            self.limiter.apply(&mut self.frame);
+            for &sample in &self.frame {
+                self.producer.push(sample).ok();
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        let moves_playhead = match command {
+            Command::TogglePlay => {
+                self.stream.toggle_play();
+                true
+            }
+            Command::SeekForwards(seconds) => {
+                self.stream.seek_forwards(seconds);
+                true
+            }
+            Command::SeekBackwards(seconds) => {
+                self.stream.seek_backwards(seconds);
+                true
+            }
+            Command::SetLoopStart => {
+                self.stream.set_loop_start();
+                false
+            }
+            Command::SetLoopEnd => {
+                self.stream.set_loop_end();
+                false
+            }
+            Command::ToggleLoop => {
+                self.stream.toggle_loop();
+                false
+            }
+            Command::CycleLoopSection(delta) => {
+                self.stream.cycle_loop_section(delta);
+                false
+            }
+            Command::ActivateLoopSection => {
+                self.stream.activate_loop_section();
+                true
+            }
+            Command::AddLoopSection => {
+                self.stream.add_loop_section();
+                false
+            }
+            Command::DeleteLoopSection => {
+                self.stream.delete_loop_section();
+                false
+            }
+            Command::RenameLoopSection(name) => {
+                self.stream.rename_loop_section(name);
+                false
+            }
+            Command::ChangeLoopGap(steps) => {
+                self.stream.change_loop_gap(steps);
+                false
+            }
+            Command::ToggleLoopGapUnit => {
+                self.stream.toggle_loop_gap_unit();
+                false
+            }
+            Command::ChangeLoopCountIn(delta) => {
+                self.stream.change_loop_count_in(delta);
+                false
+            }
+            Command::ChangeLoopBpm(delta) => {
+                self.stream.change_loop_bpm(delta);
+                false
+            }
+            Command::ChangeLoopRepetitions(delta) => {
+                self.stream.change_loop_repetitions(delta);
+                false
+            }
+            Command::NextLoopFollowAction => {
+                self.stream.next_loop_follow_action();
+                false
+            }
+            Command::ToggleTrainer => {
+                self.stream.toggle_trainer();
+                false
+            }
+            Command::MarkRepetitionMissed => {
+                self.stream.mark_repetition_missed();
+                false
+            }
+            Command::ChangeTrainerStart(delta) => {
+                self.stream.change_trainer_start(delta);
+                false
+            }
+            Command::ChangeTrainerTarget(delta) => {
+                self.stream.change_trainer_target(delta);
+                false
+            }
+            Command::ChangeTrainerStep(delta) => {
+                self.stream.change_trainer_step(delta);
+                false
+            }
+            Command::ChangeTrainerRepetitions(delta) => {
+                self.stream.change_trainer_repetitions(delta);
+                false
+            }
+            Command::SeekToBookmark(key) => {
+                self.stream.seek_to_bookmark(key);
+                true
+            }
+            Command::SetBookmark(key) => {
+                self.stream.set_bookmark(key);
+                false
+            }
+            Command::AddBookmark => {
+                self.stream.add_bookmark();
+                false
+            }
+            Command::NextBookmark => {
+                self.stream.next_bookmark();
+                true
+            }
+            Command::PreviousBookmark => {
+                self.stream.previous_bookmark();
+                true
+            }
+            Command::LabelBookmark(label) => {
+                self.stream.label_bookmark(label);
+                false
+            }
+            Command::NoteBookmark(note) => {
+                self.stream.note_bookmark(note);
+                false
+            }
+            Command::NextBookmarkColor => {
+                self.stream.next_bookmark_color();
+                false
+            }
+            Command::DeleteBookmark => {
+                self.stream.delete_bookmark();
+                false
+            }
+            Command::NextFastestSpeed => {
+                self.stream.set_next_fastest_speed();
+                false
+            }
+            Command::NextSlowestSpeed => {
+                self.stream.set_next_slowest_speed();
+                false
+            }
+            Command::ChangeLiveTempo(delta) => {
+                self.stream.change_live_tempo(delta);
+                false
+            }
+            Command::ChangeVolume(delta_db) => {
+                self.limiter.change_volume(delta_db);
+                false
+            }
+            Command::Transpose(cents) => {
+                self.stream.transpose(cents);
+                false
+            }
+            Command::ToggleVarispeed => {
+                self.stream.toggle_varispeed();
+                false
+            }
+            Command::TogglePanExtract => {
+                self.stream.toggle_pan_extract();
+                false
+            }
+            Command::MovePanExtract(delta) => {
+                self.stream.move_pan_extract(delta);
+                false
+            }
+            Command::ChangePanExtractWidth(delta) => {
+                self.stream.change_pan_extract_width(delta);
+                false
+            }
+            Command::NextChannelMode => {
+                self.stream.next_channel_mode();
+                false
+            }
+            Command::NextEqualizerPreset => {
+                self.stream.next_equalizer_preset();
+                false
+            }
+            Command::NextLayer => {
+                self.stream.next_layer();
+                false
+            }
+        };
+
+        if moves_playhead {
+            self.time_stretch.reset();
+            self.limiter.reset();
+            self.resampler.reset();
+            self.flush.store(true, Ordering::Release);
+        }
+        self.publish_output_data();
+    }
+}
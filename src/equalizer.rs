@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+    BandPass,
+    Peaking,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Band {
+    pub kind: FilterKind,
+    pub frequency: f32,
+    pub q: f32,
+    /// Only used by peaking bands
+    #[serde(default)]
+    pub gain_db: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub bands: Vec<Band>,
+}
+
+/// What is saved per song: the selected preset and any presets added by hand.
+#[derive(Serialize, Deserialize, Default)]
+pub struct EqualizerSettings {
+    pub preset: String,
+    #[serde(default)]
+    pub custom_presets: Vec<Preset>,
+}
+
+fn band(kind: FilterKind, frequency: f32, q: f32, gain_db: f32) -> Band {
+    Band {
+        kind,
+        frequency,
+        q,
+        gain_db,
+    }
+}
+
+fn builtin_presets() -> Vec<Preset> {
+    use FilterKind::*;
+    vec![
+        Preset {
+            name: "off".to_string(),
+            bands: vec![],
+        },
+        Preset {
+            name: "bass focus".to_string(),
+            bands: vec![
+                band(HighPass, 40.0, 0.707, 0.0),
+                band(LowPass, 300.0, 0.707, 0.0),
+            ],
+        },
+        Preset {
+            name: "guitar mids".to_string(),
+            bands: vec![
+                band(BandPass, 1000.0, 0.5, 0.0),
+                band(Peaking, 2500.0, 1.0, 3.0),
+            ],
+        },
+        Preset {
+            name: "vocals".to_string(),
+            bands: vec![
+                band(HighPass, 150.0, 0.707, 0.0),
+                band(LowPass, 6000.0, 0.707, 0.0),
+                band(Peaking, 3000.0, 1.0, 4.0),
+            ],
+        },
+        Preset {
+            name: "cymbals".to_string(),
+            bands: vec![band(HighPass, 5000.0, 0.707, 0.0)],
+        },
+    ]
+}
+
+/// RBJ cookbook biquad, transposed direct form II.
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn new(band: &Band, sample_rate: usize) -> Self {
+        // Keep the centre frequency clear of Nyquist
+        let frequency = band.frequency.min(sample_rate as f32 * 0.45);
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        let cos = w0.cos();
+        let alpha = w0.sin() / (2.0 * band.q);
+        let a = 10f32.powf(band.gain_db / 40.0);
+
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            FilterKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+        };
+
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// A chain of filter bands applied to every channel, chosen from presets.
+pub struct Equalizer {
+    channels: usize,
+    sample_rate: usize,
+    presets: Vec<Preset>,
+    current: usize,
+    // One filter per band per channel, band major
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(channels: usize, sample_rate: usize, settings: &EqualizerSettings) -> Self {
+        let mut presets = builtin_presets();
+        presets.extend(settings.custom_presets.iter().cloned());
+        let current = presets
+            .iter()
+            .position(|p| p.name == settings.preset)
+            .unwrap_or(0);
+
+        let mut equalizer = Equalizer {
+            channels,
+            sample_rate,
+            presets,
+            current,
+            filters: Vec::new(),
+        };
+        equalizer.build_filters();
+        equalizer
+    }
+
+    pub fn preset_name(&self) -> &str {
+        &self.presets[self.current].name
+    }
+
+    pub fn next_preset(&mut self) {
+        self.current = (self.current + 1) % self.presets.len();
+        self.build_filters();
+    }
+
+    pub fn settings(&self) -> EqualizerSettings {
+        EqualizerSettings {
+            preset: self.preset_name().to_string(),
+            custom_presets: self.presets[builtin_presets().len()..].to_vec(),
+        }
+    }
+
+    fn build_filters(&mut self) {
+        self.filters = self.presets[self.current]
+            .bands
+            .iter()
+            .flat_map(|band| (0..self.channels).map(|_| Biquad::new(band, self.sample_rate)))
+            .collect();
+    }
+
+    pub fn apply(&mut self, frame: &mut [f32]) {
+        for filters in self.filters.chunks_mut(self.channels) {
+            for (sample, filter) in frame.iter_mut().zip(filters) {
+                *sample = filter.process(*sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 48000;
+
+    fn peaking(frequency: f32, gain_db: f32) -> EqualizerSettings {
+        EqualizerSettings {
+            preset: "test".to_string(),
+            custom_presets: vec![Preset {
+                name: "test".to_string(),
+                bands: vec![band(FilterKind::Peaking, frequency, 1.0, gain_db)],
+            }],
+        }
+    }
+
+    // Steady state gain of the equalizer for a sine at `frequency`
+    fn gain_at(settings: &EqualizerSettings, frequency: f32) -> f32 {
+        let mut equalizer = Equalizer::new(1, RATE, settings);
+        let mut peak = 0.0f32;
+        for i in 0..RATE {
+            let mut frame = [(i as f32 * frequency * 2.0 * PI / RATE as f32).sin()];
+            equalizer.apply(&mut frame);
+            // Skip the filter settling in
+            if i > RATE / 2 {
+                peak = peak.max(frame[0].abs());
+            }
+        }
+        peak
+    }
+
+    fn to_db(gain: f32) -> f32 {
+        20.0 * gain.log10()
+    }
+
+    #[test]
+    fn peaking_band_has_its_gain_at_the_centre() {
+        for gain_db in [6.0, -9.0] {
+            let settings = peaking(1000.0, gain_db);
+            let centre = to_db(gain_at(&settings, 1000.0));
+            assert!((centre - gain_db).abs() < 0.05, "{} dB at centre", centre);
+            // Far from the band the signal is left alone
+            let far = to_db(gain_at(&settings, 60.0));
+            assert!(far.abs() < 0.2, "{} dB at 60 Hz", far);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// How far ahead the limiter sees peaks coming, and so its added latency
+const LOOKAHEAD_SECONDS: f32 = 0.005;
+const RELEASE_SECONDS: f32 = 0.1;
+// Highest sample value let through, just under full scale
+const CEILING: f32 = 0.98;
+pub const MIN_VOLUME_DB: f32 = -30.0;
+pub const MAX_VOLUME_DB: f32 = 12.0;
+
+/// Applies the session volume and keeps the result from clipping.
+///
+/// Gain reductions are found ahead of time through a short delay line and
+/// ramped in over the look-ahead, so peaks are caught without clicks.
+pub struct Limiter {
+    channels: usize,
+    volume_db: f32,
+    volume: f32,
+    lookahead: usize,
+    release: f32,
+    // Delayed frames, interleaved, and the gain each one needs on its own
+    delay: Vec<f32>,
+    required: Vec<f32>,
+    // Recent envelope values, averaged to smooth the gain
+    envelope: Vec<f32>,
+    last_envelope: f32,
+    position: usize,
+}
+
+impl Limiter {
+    /// `sample_rate` is the rate of the audio it is given, which is the
+    /// output rate once the engine resamples.
+    pub fn new(channels: usize, sample_rate: usize) -> Self {
+        let mut limiter = Limiter {
+            channels,
+            volume_db: 0.0,
+            volume: 1.0,
+            lookahead: 0,
+            release: 0.0,
+            delay: Vec::new(),
+            required: Vec::new(),
+            envelope: Vec::new(),
+            last_envelope: 1.0,
+            position: 0,
+        };
+        limiter.set_sample_rate(sample_rate);
+        limiter
+    }
+
+    /// Resizes the look-ahead and release for a new rate, dropping buffered audio.
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.lookahead = ((sample_rate as f32 * LOOKAHEAD_SECONDS) as usize).max(1);
+        self.release = 1.0 - (-1.0 / (RELEASE_SECONDS * sample_rate as f32)).exp();
+        self.delay = vec![0.0; self.lookahead * self.channels];
+        self.required = vec![1.0; self.lookahead];
+        self.envelope = vec![1.0; self.lookahead];
+        self.last_envelope = 1.0;
+        self.position = 0;
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        self.volume_db
+    }
+
+    pub fn change_volume(&mut self, delta_db: f32) {
+        self.volume_db = (self.volume_db + delta_db).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+        self.volume = 10f32.powf(self.volume_db / 20.0);
+    }
+
+    /// Drops buffered audio, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.delay.fill(0.0);
+        self.required.fill(1.0);
+        self.envelope.fill(1.0);
+        self.last_envelope = 1.0;
+        self.position = 0;
+    }
+
+    pub fn apply(&mut self, frame: &mut [f32]) {
+        let mut peak = 0.0f32;
+        for sample in frame.iter_mut() {
+            *sample *= self.volume;
+            peak = peak.max(sample.abs());
+        }
+        self.required[self.position] = if peak > CEILING { CEILING / peak } else { 1.0 };
+
+        // The envelope values of the last `lookahead` frames each come from a
+        // minimum over a window holding the frame that is now due out, so
+        // their average never lets that frame past the ceiling
+        let gain = (self.envelope.iter().sum::<f32>() / self.lookahead as f32).min(1.0);
+        let minimum = self.required.iter().copied().fold(1.0, f32::min);
+        let released = self.last_envelope + (1.0 - self.last_envelope) * self.release;
+        self.last_envelope = minimum.min(released);
+        self.envelope[self.position] = self.last_envelope;
+
+        // Swap the new frame in for the oldest one, which is now due out
+        let start = self.position * self.channels;
+        for (sample, delayed) in frame
+            .iter_mut()
+            .zip(&mut self.delay[start..start + self.channels])
+        {
+            std::mem::swap(sample, delayed);
+            *sample *= gain;
+        }
+        self.position = (self.position + 1) % self.lookahead;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::equalizer::{Band, Equalizer, EqualizerSettings, FilterKind, Preset};
+
+    const RATE: usize = 48000;
+
+    fn sine(frequency: f32, amplitude: f32, frame: usize) -> f32 {
+        (frame as f32 * frequency * std::f32::consts::TAU / RATE as f32).sin() * amplitude
+    }
+
+    #[test]
+    fn boosted_peaks_never_pass_the_ceiling() {
+        let boost = EqualizerSettings {
+            preset: "boost".to_string(),
+            custom_presets: vec![Preset {
+                name: "boost".to_string(),
+                bands: vec![Band {
+                    kind: FilterKind::Peaking,
+                    frequency: 1000.0,
+                    q: 1.0,
+                    gain_db: 12.0,
+                }],
+            }],
+        };
+        let mut equalizer = Equalizer::new(2, RATE, &boost);
+        let mut limiter = Limiter::new(2, RATE);
+        limiter.change_volume(MAX_VOLUME_DB);
+
+        let mut loudest = 0.0f32;
+        for i in 0..RATE {
+            // A full scale tone with sudden bursts on top, ending up tens of dB over
+            let burst = if i % 4800 < 50 { 0.8 } else { 0.0 };
+            let mut frame = [sine(1000.0, 0.9, i) + burst, sine(250.0, 0.9, i) - burst];
+            equalizer.apply(&mut frame);
+            limiter.apply(&mut frame);
+            loudest = frame
+                .iter()
+                .fold(loudest, |loudest, s| loudest.max(s.abs()));
+        }
+        // Allowing only for rounding in the averaged gain
+        assert!(loudest <= CEILING + 1e-5, "peak {}", loudest);
+        assert!(loudest > CEILING * 0.9, "peak {}", loudest);
+    }
+
+    #[test]
+    fn delays_by_the_lookahead() {
+        let mut limiter = Limiter::new(1, RATE);
+        let lookahead = (RATE as f32 * LOOKAHEAD_SECONDS) as usize;
+
+        let output: Vec<f32> = (0..lookahead * 3)
+            .map(|i| {
+                let mut frame = [if i == 10 { 0.5 } else { 0.0 }];
+                limiter.apply(&mut frame);
+                frame[0]
+            })
+            .collect();
+        assert_eq!(output.iter().position(|s| *s != 0.0), Some(10 + lookahead));
+        assert_eq!(output[10 + lookahead], 0.5);
+    }
+
+    #[test]
+    fn gain_recovers_after_a_peak() {
+        let mut limiter = Limiter::new(1, RATE);
+        let lookahead = (RATE as f32 * LOOKAHEAD_SECONDS) as usize;
+        let input: Vec<f32> = (0..RATE).map(|i| if i < 100 { 4.0 } else { 0.5 }).collect();
+        let output: Vec<f32> = input
+            .iter()
+            .map(|sample| {
+                let mut frame = [*sample];
+                limiter.apply(&mut frame);
+                frame[0]
+            })
+            .collect();
+        let gains: Vec<f32> = input
+            .iter()
+            .zip(&output[lookahead..])
+            .map(|(input, output)| output / input)
+            .collect();
+
+        // The peak is held down to the ceiling, then the gain eases back to
+        // unity over a few release times without overshooting
+        let release = (RELEASE_SECONDS * RATE as f32) as usize;
+        assert!(
+            (gains[50] * 4.0 - CEILING).abs() < 1e-3,
+            "gain {}",
+            gains[50]
+        );
+        assert!(gains[100] < 0.5, "gain {}", gains[100]);
+        assert!(gains[100..].windows(2).all(|w| w[1] >= w[0] - 1e-6));
+        assert!((0.5..0.99).contains(&gains[100 + release]));
+        assert!((0.99..=1.0).contains(&gains[100 + 5 * release]));
+    }
+}
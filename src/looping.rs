@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+const CLICK_SECONDS: f32 = 0.05;
+const CLICK_DECAY_SECONDS: f32 = 0.01;
+const CLICK_HZ: f32 = 1000.0;
+const ACCENT_CLICK_HZ: f32 = 1500.0;
+const CLICK_LEVEL: f32 = 0.5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum GapUnit {
+    Seconds,
+    Beats,
+}
+
+/// What playback does once a loop has played its repetitions.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum FollowAction {
+    #[default]
+    Stop,
+    Continue,
+    /// Jump to the bookmark on this number key
+    JumpToBookmark(u8),
+}
+
+impl FollowAction {
+    /// Stop, continue, then each bookmark in keyboard order.
+    pub fn next(self) -> Self {
+        match self {
+            FollowAction::Stop => FollowAction::Continue,
+            FollowAction::Continue => FollowAction::JumpToBookmark(1),
+            FollowAction::JumpToBookmark(0) => FollowAction::Stop,
+            FollowAction::JumpToBookmark(9) => FollowAction::JumpToBookmark(0),
+            FollowAction::JumpToBookmark(key) => FollowAction::JumpToBookmark(key + 1),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            FollowAction::Stop => "stop".to_string(),
+            FollowAction::Continue => "continue".to_string(),
+            FollowAction::JumpToBookmark(key) => format!("jump to bookmark {}", key),
+        }
+    }
+}
+
+/// How the speed trainer raises the tempo, as fractions of the original.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrainerSettings {
+    pub start_tempo: f64,
+    pub step: f64,
+    pub target_tempo: f64,
+    /// Clean repetitions needed before each step up
+    pub repetitions: u32,
+    /// Fastest tempo reached so far on this loop
+    pub reached_tempo: Option<f64>,
+}
+
+impl Default for TrainerSettings {
+    fn default() -> Self {
+        TrainerSettings {
+            start_tempo: 0.7,
+            step: 0.05,
+            target_tempo: 1.0,
+            repetitions: 3,
+            reached_tempo: None,
+        }
+    }
+}
+
+/// What happens between repetitions of a loop.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoopSettings {
+    pub gap: f32,
+    pub gap_unit: GapUnit,
+    pub count_in_beats: u32,
+    /// Tempo of the song at its original speed, used for beat lengths
+    pub bpm: f32,
+    #[serde(default)]
+    pub trainer: TrainerSettings,
+    /// Times to play the loop, or 0 to repeat until it is turned off
+    #[serde(default)]
+    pub repetitions: u32,
+    #[serde(default)]
+    pub follow_action: FollowAction,
+}
+
+impl Default for LoopSettings {
+    fn default() -> Self {
+        LoopSettings {
+            gap: 0.0,
+            gap_unit: GapUnit::Seconds,
+            count_in_beats: 0,
+            bpm: 120.0,
+            trainer: TrainerSettings::default(),
+            repetitions: 0,
+            follow_action: FollowAction::Stop,
+        }
+    }
+}
+
+impl LoopSettings {
+    pub fn has_interlude(&self) -> bool {
+        self.gap > 0.0 || self.count_in_beats > 0
+    }
+
+    pub fn describe_gap(&self) -> String {
+        match self.gap_unit {
+            GapUnit::Seconds => format!("{:.1}s", self.gap),
+            GapUnit::Beats => format!("{} beats", self.gap),
+        }
+    }
+
+    pub fn describe_iteration(&self, iteration: u32) -> String {
+        if self.repetitions == 0 {
+            format!("iteration {}, repeating until turned off", iteration)
+        } else {
+            format!(
+                "iteration {}/{}, then {}",
+                iteration,
+                self.repetitions,
+                self.follow_action.describe()
+            )
+        }
+    }
+}
+
+/// A named loop, saved with the others in the song directory.
+#[derive(Serialize, Deserialize, Default)]
+pub struct LoopSection {
+    pub name: String,
+    /// Seconds into the original recording
+    pub start: f64,
+    pub end: f64,
+    pub settings: LoopSettings,
+}
+
+impl LoopSection {
+    pub fn new(name: String) -> Self {
+        LoopSection {
+            name,
+            ..Default::default()
+        }
+    }
+}
+
+/// Silence and metronome clicks played before a loop restarts.
+pub struct Interlude {
+    position: u64,
+    gap_frames: u64,
+    beat_frames: u64,
+    beats: u32,
+    sample_rate: f32,
+}
+
+impl Interlude {
+    /// `beat_frames` and `second_frames` are lengths in frames of the file
+    /// being played, so the interlude follows its speed.
+    pub fn new(
+        settings: &LoopSettings,
+        beat_frames: u64,
+        second_frames: u64,
+        sample_rate: usize,
+    ) -> Self {
+        let gap_frames = match settings.gap_unit {
+            GapUnit::Seconds => (settings.gap * second_frames as f32) as u64,
+            GapUnit::Beats => (settings.gap * beat_frames as f32) as u64,
+        };
+        Interlude {
+            position: 0,
+            gap_frames,
+            beat_frames,
+            beats: settings.count_in_beats,
+            sample_rate: sample_rate as f32,
+        }
+    }
+
+    /// Writes the next frame, or returns false once the interlude is over.
+    pub fn next_frame(&mut self, frame: &mut [f32]) -> bool {
+        let count_in_frames = self.beat_frames * self.beats as u64;
+        if self.position >= self.gap_frames + count_in_frames {
+            return false;
+        }
+
+        let mut sample = 0.0;
+        if self.position >= self.gap_frames && self.beat_frames > 0 {
+            let count_in_position = self.position - self.gap_frames;
+            let beat = count_in_position / self.beat_frames;
+            let t = (count_in_position % self.beat_frames) as f32 / self.sample_rate;
+            if t < CLICK_SECONDS {
+                let frequency = if beat == 0 { ACCENT_CLICK_HZ } else { CLICK_HZ };
+                sample = CLICK_LEVEL
+                    * (-t / CLICK_DECAY_SECONDS).exp()
+                    * (2.0 * PI * frequency * t).sin();
+            }
+        }
+        frame.fill(sample);
+        self.position += 1;
+        true
+    }
+}
+
+/// A running speed trainer session.
+pub struct Trainer {
+    pub tempo: f64,
+    pub clean_repetitions: u32,
+    missed: bool,
+    // Live tempo to go back to when the trainer stops
+    pub previous_live_tempo: f64,
+}
+
+impl Trainer {
+    pub fn new(settings: &TrainerSettings, previous_live_tempo: f64) -> Self {
+        Trainer {
+            tempo: settings.start_tempo,
+            clean_repetitions: 0,
+            missed: false,
+            previous_live_tempo,
+        }
+    }
+
+    /// Keeps the repetition in progress from counting as clean.
+    pub fn mark_missed(&mut self) {
+        self.missed = true;
+    }
+
+    /// Counts a finished repetition and returns true if the tempo went up.
+    /// The tempo stops at `max_tempo` if that is short of the target.
+    pub fn complete_repetition(&mut self, settings: &mut TrainerSettings, max_tempo: f64) -> bool {
+        if self.missed {
+            self.missed = false;
+            self.clean_repetitions = 0;
+            return false;
+        }
+
+        let target = settings.target_tempo.min(max_tempo);
+        self.clean_repetitions += 1;
+        if self.clean_repetitions < settings.repetitions || self.tempo >= target {
+            return false;
+        }
+
+        self.clean_repetitions = 0;
+        self.tempo = (self.tempo + settings.step).min(target);
+        if settings
+            .reached_tempo
+            .is_none_or(|reached| self.tempo > reached)
+        {
+            settings.reached_tempo = Some(self.tempo);
+        }
+        true
+    }
+
+    pub fn describe(&self, settings: &TrainerSettings) -> String {
+        format!(
+            "{:.0}% rep {}/{}{}",
+            self.tempo * 100.0,
+            self.clean_repetitions,
+            settings.repetitions,
+            if self.missed { " (missed)" } else { "" }
+        )
+    }
+}
+
+impl TrainerSettings {
+    pub fn describe(&self) -> String {
+        let reached = match self.reached_tempo {
+            Some(tempo) => format!(", best {:.0}%", tempo * 100.0),
+            None => String::new(),
+        };
+        format!(
+            "{:.0}% to {:.0}%, +{:.0}% every {} clean{}",
+            self.start_tempo * 100.0,
+            self.target_tempo * 100.0,
+            self.step * 100.0,
+            self.repetitions,
+            reached
+        )
+    }
+}
@@ -1,7 +1,7 @@
+use engine::{Command, EngineHandle, EngineOutputData};
 use output::output_stream;
 use save_data::SongData;
 use std::io;
-use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
@@ -9,23 +9,62 @@ use ratatui::{
     DefaultTerminal, Frame,
     buffer::Buffer,
     layout::Rect,
-    style::Stylize,
+    style::{Color, Stylize},
     symbols::border,
-    text::{Line, Text},
+    text::{Line, Span, Text},
     widgets::{Block, Paragraph, Widget},
 };
+mod align;
 mod audio_stream;
+mod bookmarks;
+mod channel_matrix;
+mod decoder;
+mod engine;
+mod equalizer;
+mod limiter;
+mod looping;
+mod memory_decoder;
+mod opus_decoder;
 mod output;
+mod pan_extract;
+mod process_hpss;
 mod process_time_warp;
+mod resampler;
 mod save_data;
-use audio_stream::{AudioStream, Digits};
+mod stft;
+mod symphonia_decoder;
+mod time_stretch;
+mod wav;
+use audio_stream::{AudioStream, BookmarkOutputData};
+use bookmarks::BookmarkColor;
 use clap::Parser;
+use resampler::ResampleQuality;
 
 #[derive(Parser, Debug)]
 struct Cli {
     #[arg(long)]
     process_speed: Option<Vec<f32>>,
 
+    /// Split the song into harmonic and percussive layers for playback
+    #[arg(long)]
+    separate_layers: bool,
+
+    /// Decode the song and its speed versions into memory before playing
+    #[arg(long)]
+    preload: bool,
+
+    /// Quality used when the file's sample rate differs from the output device
+    #[arg(long, value_enum, default_value_t = ResampleQuality::High)]
+    resample_quality: ResampleQuality,
+
+    /// Length of the crossfade at the loop seam in milliseconds, 0 for a hard cut
+    #[arg(long, default_value_t = 20)]
+    loop_crossfade_ms: u32,
+
+    /// Move new loop points to the nearest zero crossing
+    #[arg(long)]
+    snap_loops: bool,
+
     file_path: Option<String>,
 }
 
@@ -43,7 +82,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         None => (),
         Some(speed) => {
             for speed in speed {
-                let updated_song_data = SongData::from_wave_file(&filename);
+                let updated_song_data = SongData::from_file(&filename);
                 println!("Processing speed version: {}", speed);
                 let result = process_time_warp::process(&updated_song_data, speed);
                 match result {
@@ -57,14 +96,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    let audio_stream = Arc::new(Mutex::new(AudioStream::from_wave_file(&filename)));
-    let _stream = output_stream(audio_stream.clone());
+    if args.separate_layers {
+        let song_data = SongData::from_file(&filename);
+        println!("Separating harmonic and percussive layers");
+        match process_hpss::process(&song_data) {
+            Ok(()) => println!("Done!"),
+            Err(message) => eprintln!("Error separating layers: {}", message),
+        }
+        return Ok(());
+    }
+
+    let mut audio_stream = AudioStream::from_file(&filename, args.preload);
+    audio_stream.set_loop_crossfade(args.loop_crossfade_ms as f32 / 1000.0);
+    audio_stream.set_snap_loop_points(args.snap_loops);
+    let (engine, playback_buffer) = engine::start(audio_stream, args.resample_quality);
+    let _stream = output_stream(playback_buffer);
 
     let mut terminal = ratatui::init();
     let app_result = App {
-        stream: audio_stream,
+        engine,
         exit: false,
         mode: Mode::Normal,
+        text_input: String::new(),
     }
     .run(&mut terminal);
     ratatui::restore();
@@ -75,11 +128,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 enum Mode {
     Normal,
     SetBookmark,
+    Loop,
+    NameLoop,
+    LabelBookmark,
+    NoteBookmark,
 }
 
 pub struct App {
-    stream: Arc<Mutex<AudioStream>>,
+    engine: EngineHandle,
     mode: Mode,
+    // Loop section name, bookmark label or note being typed
+    text_input: String,
     exit: bool,
 }
 
@@ -112,47 +171,136 @@ impl App {
         match self.mode {
             Mode::Normal => match key_event.code {
                 KeyCode::Char('q') => self.exit(),
-                KeyCode::Char('k') => self.stream.lock().unwrap().toggle_play(),
-                KeyCode::Char('j') => self.stream.lock().unwrap().seek_backwards(5),
-                KeyCode::Char('l') => self.stream.lock().unwrap().seek_forwards(5),
-                KeyCode::Char('u') => self.stream.lock().unwrap().set_loop_start(),
-                KeyCode::Char('o') => self.stream.lock().unwrap().set_loop_end(),
-                KeyCode::Char('i') => self.stream.lock().unwrap().toggle_loop(),
-                KeyCode::Char('1') => self.stream.lock().unwrap().seek_to_bookmark(Digits::One),
-                KeyCode::Char('2') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Two),
-                KeyCode::Char('3') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Three),
-                KeyCode::Char('4') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Four),
-                KeyCode::Char('5') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Five),
-                KeyCode::Char('6') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Six),
-                KeyCode::Char('7') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Seven),
-                KeyCode::Char('8') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Eight),
-                KeyCode::Char('9') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Nine),
-                KeyCode::Char('0') => self.stream.lock().unwrap().seek_to_bookmark(Digits::Zero),
-                KeyCode::Char('w') => self.stream.lock().unwrap().set_bookmark(Digits::One),
+                KeyCode::Up => self.engine.send(Command::ChangeVolume(1.0)),
+                KeyCode::Down => self.engine.send(Command::ChangeVolume(-1.0)),
+                KeyCode::Char('k') => self.engine.send(Command::TogglePlay),
+                KeyCode::Char('j') => self.engine.send(Command::SeekBackwards(5)),
+                KeyCode::Char('l') => self.engine.send(Command::SeekForwards(5)),
+                KeyCode::Char('u') => self.engine.send(Command::SetLoopStart),
+                KeyCode::Char('o') => self.engine.send(Command::SetLoopEnd),
+                KeyCode::Char('i') => self.engine.send(Command::ToggleLoop),
+                KeyCode::Char(key @ '0'..='9') => {
+                    self.engine.send(Command::SeekToBookmark(digit(key)))
+                }
+                KeyCode::Char('n') => self.engine.send(Command::NextBookmark),
+                KeyCode::Char('N') => self.engine.send(Command::PreviousBookmark),
+                KeyCode::Char('w') => self.engine.send(Command::SetBookmark(1)),
                 KeyCode::Char('b') => self.mode = Mode::SetBookmark,
-                KeyCode::Char('.') => self.stream.lock().unwrap().set_next_fastest_speed(),
-                KeyCode::Char(',') => self.stream.lock().unwrap().set_next_slowest_speed(),
+                KeyCode::Char('r') => self.mode = Mode::Loop,
+                KeyCode::Char('.') => self.engine.send(Command::NextFastestSpeed),
+                KeyCode::Char(',') => self.engine.send(Command::NextSlowestSpeed),
+                KeyCode::Char(']') => self.engine.send(Command::ChangeLiveTempo(0.05)),
+                KeyCode::Char('[') => self.engine.send(Command::ChangeLiveTempo(-0.05)),
+                KeyCode::Char('}') => self.engine.send(Command::ChangeLiveTempo(0.01)),
+                KeyCode::Char('{') => self.engine.send(Command::ChangeLiveTempo(-0.01)),
+                KeyCode::Char('v') => self.engine.send(Command::ToggleVarispeed),
+                KeyCode::Char('c') => self.engine.send(Command::NextChannelMode),
+                KeyCode::Char('p') => self.engine.send(Command::TogglePanExtract),
+                KeyCode::Char('(') => self.engine.send(Command::MovePanExtract(-0.1)),
+                KeyCode::Char(')') => self.engine.send(Command::MovePanExtract(0.1)),
+                KeyCode::Char('<') => self.engine.send(Command::ChangePanExtractWidth(-0.05)),
+                KeyCode::Char('>') => self.engine.send(Command::ChangePanExtractWidth(0.05)),
+                KeyCode::Char('e') => self.engine.send(Command::NextEqualizerPreset),
+                KeyCode::Char('h') => self.engine.send(Command::NextLayer),
+                KeyCode::Char('=') => self.engine.send(Command::Transpose(100)),
+                KeyCode::Char('-') => self.engine.send(Command::Transpose(-100)),
+                KeyCode::Char('+') => self.engine.send(Command::Transpose(1)),
+                KeyCode::Char('_') => self.engine.send(Command::Transpose(-1)),
                 _ => {
                     dbg!("Unhandled key event: {:?}", key_event);
                 }
             },
             Mode::SetBookmark => match key_event.code {
-                KeyCode::Char('j') => self.stream.lock().unwrap().seek_backwards(5),
-                KeyCode::Char('l') => self.stream.lock().unwrap().seek_forwards(5),
-                KeyCode::Char('k') => self.stream.lock().unwrap().toggle_play(),
-                KeyCode::Char('1') => self.stream.lock().unwrap().set_bookmark(Digits::One),
-                KeyCode::Char('2') => self.stream.lock().unwrap().set_bookmark(Digits::Two),
-                KeyCode::Char('3') => self.stream.lock().unwrap().set_bookmark(Digits::Three),
-                KeyCode::Char('4') => self.stream.lock().unwrap().set_bookmark(Digits::Four),
-                KeyCode::Char('5') => self.stream.lock().unwrap().set_bookmark(Digits::Five),
-                KeyCode::Char('6') => self.stream.lock().unwrap().set_bookmark(Digits::Six),
-                KeyCode::Char('7') => self.stream.lock().unwrap().set_bookmark(Digits::Seven),
-                KeyCode::Char('8') => self.stream.lock().unwrap().set_bookmark(Digits::Eight),
-                KeyCode::Char('9') => self.stream.lock().unwrap().set_bookmark(Digits::Nine),
-                KeyCode::Char('0') => self.stream.lock().unwrap().set_bookmark(Digits::Zero),
+                KeyCode::Char('j') => self.engine.send(Command::SeekBackwards(5)),
+                KeyCode::Char('l') => self.engine.send(Command::SeekForwards(5)),
+                KeyCode::Char('k') => self.engine.send(Command::TogglePlay),
+                KeyCode::Char(key @ '0'..='9') => {
+                    self.engine.send(Command::SetBookmark(digit(key)))
+                }
+                KeyCode::Char('a') => self.engine.send(Command::AddBookmark),
+                KeyCode::Char('n') => self.engine.send(Command::NextBookmark),
+                KeyCode::Char('N') => self.engine.send(Command::PreviousBookmark),
+                KeyCode::Char('c') => self.engine.send(Command::NextBookmarkColor),
+                KeyCode::Char('x') => self.engine.send(Command::DeleteBookmark),
+                KeyCode::Char('e') => {
+                    self.text_input.clear();
+                    self.mode = Mode::LabelBookmark;
+                }
+                KeyCode::Char('t') => {
+                    self.text_input.clear();
+                    self.mode = Mode::NoteBookmark;
+                }
                 KeyCode::Char('b') => self.mode = Mode::Normal,
                 _ => {}
             },
+            Mode::Loop => match key_event.code {
+                KeyCode::Char('j') => self.engine.send(Command::SeekBackwards(5)),
+                KeyCode::Char('l') => self.engine.send(Command::SeekForwards(5)),
+                KeyCode::Char('k') => self.engine.send(Command::TogglePlay),
+                KeyCode::Char('u') => self.engine.send(Command::SetLoopStart),
+                KeyCode::Char('o') => self.engine.send(Command::SetLoopEnd),
+                KeyCode::Char('i') => self.engine.send(Command::ToggleLoop),
+                KeyCode::Left => self.engine.send(Command::CycleLoopSection(-1)),
+                KeyCode::Right => self.engine.send(Command::CycleLoopSection(1)),
+                KeyCode::Enter => self.engine.send(Command::ActivateLoopSection),
+                KeyCode::Char('w') => self.engine.send(Command::AddLoopSection),
+                KeyCode::Delete => self.engine.send(Command::DeleteLoopSection),
+                KeyCode::Char('e') => {
+                    self.text_input.clear();
+                    self.mode = Mode::NameLoop;
+                }
+                KeyCode::Char('[') => self.engine.send(Command::ChangeLoopGap(-1)),
+                KeyCode::Char(']') => self.engine.send(Command::ChangeLoopGap(1)),
+                KeyCode::Char('g') => self.engine.send(Command::ToggleLoopGapUnit),
+                KeyCode::Char('{') => self.engine.send(Command::ChangeLoopCountIn(-1)),
+                KeyCode::Char('}') => self.engine.send(Command::ChangeLoopCountIn(1)),
+                KeyCode::Char('-') => self.engine.send(Command::ChangeLoopBpm(-1.0)),
+                KeyCode::Char('=') => self.engine.send(Command::ChangeLoopBpm(1.0)),
+                KeyCode::Char('_') => self.engine.send(Command::ChangeLoopBpm(-10.0)),
+                KeyCode::Char('+') => self.engine.send(Command::ChangeLoopBpm(10.0)),
+                KeyCode::Char(',') => self.engine.send(Command::ChangeLoopRepetitions(-1)),
+                KeyCode::Char('.') => self.engine.send(Command::ChangeLoopRepetitions(1)),
+                KeyCode::Char('y') => self.engine.send(Command::NextLoopFollowAction),
+                KeyCode::Char('t') => self.engine.send(Command::ToggleTrainer),
+                KeyCode::Char('x') => self.engine.send(Command::MarkRepetitionMissed),
+                KeyCode::Char('a') => self.engine.send(Command::ChangeTrainerStart(-0.05)),
+                KeyCode::Char('s') => self.engine.send(Command::ChangeTrainerStart(0.05)),
+                KeyCode::Char('d') => self.engine.send(Command::ChangeTrainerTarget(-0.05)),
+                KeyCode::Char('f') => self.engine.send(Command::ChangeTrainerTarget(0.05)),
+                KeyCode::Char('z') => self.engine.send(Command::ChangeTrainerStep(-0.01)),
+                KeyCode::Char('c') => self.engine.send(Command::ChangeTrainerStep(0.01)),
+                KeyCode::Char('n') => self.engine.send(Command::ChangeTrainerRepetitions(-1)),
+                KeyCode::Char('m') => self.engine.send(Command::ChangeTrainerRepetitions(1)),
+                KeyCode::Char('r') => self.mode = Mode::Normal,
+                _ => {}
+            },
+            Mode::NameLoop | Mode::LabelBookmark | Mode::NoteBookmark => {
+                self.handle_text_input(key_event)
+            }
+        }
+    }
+
+    fn handle_text_input(&mut self, key_event: KeyEvent) {
+        let return_mode = match self.mode {
+            Mode::NameLoop => Mode::Loop,
+            _ => Mode::SetBookmark,
+        };
+        match key_event.code {
+            KeyCode::Char(c) => self.text_input.push(c),
+            KeyCode::Backspace => {
+                self.text_input.pop();
+            }
+            KeyCode::Enter => {
+                let text = std::mem::take(&mut self.text_input);
+                self.engine.send(match self.mode {
+                    Mode::NameLoop => Command::RenameLoopSection(text),
+                    Mode::LabelBookmark => Command::LabelBookmark(text),
+                    _ => Command::NoteBookmark(text),
+                });
+                self.mode = return_mode;
+            }
+            KeyCode::Esc => self.mode = return_mode,
+            _ => {}
         }
     }
 
@@ -189,27 +337,98 @@ impl Widget for &App {
             "<i>".blue().bold(),
             " Jump to Bookmark ".into(),
             "<0-9>".blue().bold(),
+            " Next/Previous Bookmark ".into(),
+            "<n N>".blue().bold(),
             " Bookmark Mode ".into(),
             "<b>".blue().bold(),
+            " Loop Mode ".into(),
+            "<r>".blue().bold(),
             " Speed Mode ".into(),
             "<s>".blue().bold(),
         ];
         let bookmark_instructions = vec![
             " Set Bookmark ".into(),
             "<0-9>".blue().bold(),
+            " Add ".into(),
+            "<a>".blue().bold(),
+            " Next/Previous ".into(),
+            "<n N>".blue().bold(),
+            " Label ".into(),
+            "<e>".blue().bold(),
+            " Note ".into(),
+            "<t>".blue().bold(),
+            " Color ".into(),
+            "<c>".blue().bold(),
+            " Delete ".into(),
+            "<x>".blue().bold(),
             " Normal Mode ".into(),
             "<b>".blue().bold(),
         ];
+        let loop_mode_instructions = vec![
+            " Section ".into(),
+            "<left right>".blue().bold(),
+            " Play Section ".into(),
+            "<enter>".blue().bold(),
+            " New ".into(),
+            "<w>".blue().bold(),
+            " Rename ".into(),
+            "<e>".blue().bold(),
+            " Delete ".into(),
+            "<del>".blue().bold(),
+            " Gap ".into(),
+            "<[ ]>".blue().bold(),
+            " Seconds/Beats ".into(),
+            "<g>".blue().bold(),
+            " Count-in ".into(),
+            "<{ }>".blue().bold(),
+            " BPM ".into(),
+            "<- = _ +>".blue().bold(),
+            " Repetitions ".into(),
+            "<, .>".blue().bold(),
+            " Then ".into(),
+            "<y>".blue().bold(),
+            " Trainer ".into(),
+            "<t>".blue().bold(),
+            " Missed ".into(),
+            "<x>".blue().bold(),
+            " Start ".into(),
+            "<a s>".blue().bold(),
+            " Target ".into(),
+            "<d f>".blue().bold(),
+            " Step ".into(),
+            "<z c>".blue().bold(),
+            " Reps ".into(),
+            "<n m>".blue().bold(),
+            " Normal Mode ".into(),
+            "<r>".blue().bold(),
+        ];
         let mode_instructions = match self.mode {
             Mode::Normal => Line::from(loop_instructions),
             Mode::SetBookmark => Line::from(bookmark_instructions),
+            Mode::Loop => Line::from(loop_mode_instructions),
+            Mode::NameLoop | Mode::LabelBookmark | Mode::NoteBookmark => Line::from(vec![
+                match self.mode {
+                    Mode::NameLoop => " Name: ".into(),
+                    Mode::LabelBookmark => " Label: ".into(),
+                    _ => " Note: ".into(),
+                },
+                self.text_input.as_str().into(),
+                " Save ".into(),
+                "<enter>".blue().bold(),
+                " Cancel ".into(),
+                "<esc>".blue().bold(),
+            ]),
         };
 
         let block = Block::bordered()
             .title(title.centered())
             .border_set(border::THICK);
 
-        let output_data = self.stream.lock().unwrap().output_data();
+        let EngineOutputData {
+            stream: output_data,
+            output_format,
+            volume,
+        } = self.engine.output_data();
 
         let next_fastest_output = match output_data.next_fastest_speed {
             Some(speed) => format!("[>] {}", speed.speed),
@@ -224,13 +443,71 @@ impl Widget for &App {
         let mode_display = match self.mode {
             Mode::Normal => "Normal".red(),
             Mode::SetBookmark => "Bookmark".red(),
+            Mode::Loop => "Loop".red(),
+            Mode::NameLoop => "Name Loop".red(),
+            Mode::LabelBookmark => "Label Bookmark".red(),
+            Mode::NoteBookmark => "Bookmark Note".red(),
         };
 
         let counter_text = Text::from(vec![
-            Line::from(vec!["Position: ".into(), output_data.current_time.red()]),
+            Line::from(vec![
+                "Position: ".into(),
+                output_data.current_time.red(),
+                output_data.error.red(),
+            ]),
             Line::from(vec![
                 "Speed: ".into(),
                 output_data.current_speed.speed.to_string().red(),
+                " Volume: ".into(),
+                volume.red(),
+                " <up/down>".blue().bold(),
+            ]),
+            Line::from(vec![
+                "Live tempo: ".into(),
+                output_data.live_tempo.red(),
+                " (".into(),
+                output_data.live_tempo_mode.red(),
+                ")".into(),
+                " <[ ]>".blue().bold(),
+                " 5% ".into(),
+                "<{ }>".blue().bold(),
+                " 1% ".into(),
+                "<v>".blue().bold(),
+                " varispeed".into(),
+            ]),
+            Line::from(vec![
+                "Pan extract: ".into(),
+                output_data.pan_extract.red(),
+                " <p>".blue().bold(),
+                " toggle ".into(),
+                "<( )>".blue().bold(),
+                " position ".into(),
+                "<< >>".blue().bold(),
+                " width".into(),
+            ]),
+            Line::from(vec![
+                "Channels: ".into(),
+                output_data.channel_mode.red(),
+                " <c>".blue().bold(),
+                " EQ: ".into(),
+                output_data.equalizer_preset.red(),
+                " <e>".blue().bold(),
+                " Layer: ".into(),
+                output_data.layer.red(),
+                " <h>".blue().bold(),
+            ]),
+            Line::from(vec![
+                "Transpose: ".into(),
+                output_data.transpose.red(),
+                " <- =>".blue().bold(),
+                " semitone ".into(),
+                "<_ +>".blue().bold(),
+                " cent".into(),
+            ]),
+            Line::from(vec!["Output: ".into(), output_format.red()]),
+            Line::from(vec![
+                "Loop section: ".into(),
+                output_data.loop_section.red(),
             ]),
             Line::from(vec![
                 "loop start: ".into(),
@@ -239,30 +516,17 @@ impl Widget for &App {
                 output_data.loop_end.red(),
                 " active: ".into(),
                 output_data.is_looping.red(),
+                " ".into(),
+                output_data.loop_iteration.red(),
             ]),
-            Line::from(vec!["Mode: ".into(), mode_display.into()]),
             Line::from(vec![
-                "Bookmarks: [1] ".into(),
-                output_data.bookmark_1.red(),
-                " [2] ".into(),
-                output_data.bookmark_2.red(),
-                " [3] ".into(),
-                output_data.bookmark_3.red(),
-                " [4] ".into(),
-                output_data.bookmark_4.red(),
-                " [5] ".into(),
-                output_data.bookmark_5.red(),
-                " [6] ".into(),
-                output_data.bookmark_6.red(),
-                " [7] ".into(),
-                output_data.bookmark_7.red(),
-                " [8] ".into(),
-                output_data.bookmark_8.red(),
-                " [9] ".into(),
-                output_data.bookmark_9.red(),
-                " [0] ".into(),
-                output_data.bookmark_0.red(),
+                "Between repetitions: ".into(),
+                output_data.loop_interlude.red(),
             ]),
+            Line::from(vec!["Trainer: ".into(), output_data.trainer.red()]),
+            Line::from(vec!["Mode: ".into(), mode_display.into()]),
+            Line::from(bookmark_spans(&output_data.bookmarks)),
+            Line::from(vec!["Note: ".into(), output_data.bookmark_note.red()]),
             common_instructions,
             Line::from(vec![next_slowest_output.into(), next_fastest_output.into()]),
             mode_instructions,
@@ -274,3 +538,37 @@ impl Widget for &App {
             .render(area, buf);
     }
 }
+
+// Each bookmark in its color, led by its number key if it has one
+fn bookmark_spans(bookmarks: &[BookmarkOutputData]) -> Vec<Span<'static>> {
+    let mut spans = vec![Span::from("Bookmarks:")];
+    for bookmark in bookmarks {
+        let slot = match bookmark.slot {
+            Some(key) => format!(" [{}] ", key),
+            None => " ".to_string(),
+        };
+        let mut label = Span::from(format!("{} {}", bookmark.label, bookmark.time))
+            .fg(bookmark_color(bookmark.color));
+        if bookmark.selected {
+            label = label.bold().underlined();
+        }
+        spans.push(slot.into());
+        spans.push(label);
+    }
+    spans
+}
+
+fn bookmark_color(color: BookmarkColor) -> Color {
+    match color {
+        BookmarkColor::Red => Color::Red,
+        BookmarkColor::Yellow => Color::Yellow,
+        BookmarkColor::Green => Color::Green,
+        BookmarkColor::Cyan => Color::Cyan,
+        BookmarkColor::Blue => Color::Blue,
+        BookmarkColor::Magenta => Color::Magenta,
+    }
+}
+
+fn digit(key: char) -> u8 {
+    key.to_digit(10).expect("not a digit") as u8
+}
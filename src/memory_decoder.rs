@@ -0,0 +1,75 @@
+use crate::decoder::{self, AudioSpec, Decoder};
+use std::path::Path;
+
+// Frames decoded per read while loading a track into memory
+const LOAD_CHUNK_FRAMES: usize = 4096;
+
+/// Holds a fully decoded track in RAM so reads and seeks are plain indexing.
+pub struct MemoryDecoder {
+    samples: Vec<f32>,
+    spec: AudioSpec,
+    position: u64,
+}
+
+impl MemoryDecoder {
+    pub fn from_decoder(source: &mut dyn Decoder) -> Result<Self, String> {
+        let spec = source.spec();
+        source.seek(0)?;
+
+        let mut samples = Vec::with_capacity(source.duration() as usize * spec.channels);
+        let mut chunk = vec![0.0; LOAD_CHUNK_FRAMES * spec.channels];
+        loop {
+            let frames = source.read_frames(&mut chunk);
+            samples.extend_from_slice(&chunk[..frames * spec.channels]);
+            if frames < LOAD_CHUNK_FRAMES {
+                break;
+            }
+        }
+
+        Ok(MemoryDecoder {
+            samples,
+            spec,
+            position: 0,
+        })
+    }
+
+    /// The whole track, interleaved.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+}
+
+impl Decoder for MemoryDecoder {
+    fn open(path: &Path) -> Result<Self, String> {
+        let mut source = decoder::open(path)?;
+        Self::from_decoder(source.as_mut())
+    }
+
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    fn duration(&self) -> u64 {
+        (self.samples.len() / self.spec.channels) as u64
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let start = self.position as usize * self.spec.channels;
+        let available = self.samples.len() - start;
+        let len = (buffer.len() / self.spec.channels * self.spec.channels).min(available);
+
+        buffer[..len].copy_from_slice(&self.samples[start..start + len]);
+        let frames = len / self.spec.channels;
+        self.position += frames as u64;
+        frames
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.position = frame.min(self.duration());
+        Ok(())
+    }
+}
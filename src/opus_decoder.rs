@@ -0,0 +1,225 @@
+use audiopus::coder::{Decoder as LibOpus, GenericCtl};
+use audiopus::packet::Packet as OpusPacket;
+use audiopus::{Channels, MutSignals, SampleRate};
+use std::sync::{Mutex, PoisonError};
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CODEC_TYPE_OPUS, CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult,
+};
+use symphonia::core::errors::{Error, Result, unsupported_error};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+// Opus always decodes at 48 kHz, whatever rate the source was encoded from
+const SAMPLE_RATE: u32 = 48_000;
+// The longest packet Opus allows holds 120 ms
+const MAX_PACKET_FRAMES: usize = 5760;
+// Offsets into the OpusHead that symphonia passes as extra data
+const HEAD_PRE_SKIP_OFFSET: usize = 10;
+const HEAD_GAIN_OFFSET: usize = 16;
+/// Audio to decode ahead of a seek target so the decoder has settled by then.
+pub const SEEK_PRE_ROLL: u64 = 3840;
+
+/// Frames the encoder put before the start of the track. Symphonia's Ogg
+/// reader counts them in timestamps and lengths without trimming them.
+pub fn pre_skip(params: &CodecParameters) -> u64 {
+    params
+        .extra_data
+        .as_deref()
+        .and_then(|head| head.get(HEAD_PRE_SKIP_OFFSET..HEAD_PRE_SKIP_OFFSET + 2))
+        .map_or(0, |bytes| {
+            u64::from(u16::from_le_bytes([bytes[0], bytes[1]]))
+        })
+}
+
+/// Decodes Opus packets through libopus, so symphonia's Ogg reader can play
+/// `.opus` files. Symphonia only demuxes Opus; it has no decoder of its own.
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus decoders may move between threads but not be shared, while
+    // symphonia asks for Sync. Decoding takes &mut self, so the lock is
+    // never actually taken.
+    decoder: Mutex<LibOpus>,
+    channels: usize,
+    interleaved: Vec<f32>,
+    buffer: AudioBuffer<f32>,
+}
+
+impl OpusDecoder {
+    fn decode_packet(&mut self, packet: &Packet) -> Result<()> {
+        self.buffer.clear();
+
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let input = OpusPacket::try_from(packet.buf())
+            .map_err(|_| Error::DecodeError("opus: empty packet"))?;
+        let output = MutSignals::try_from(&mut self.interleaved[..])
+            .map_err(|_| Error::DecodeError("opus: no room to decode into"))?;
+        let frames = decoder
+            .decode_float(Some(input), output, false)
+            .map_err(|_| Error::DecodeError("opus: invalid packet"))?;
+
+        self.buffer.render_reserved(Some(frames));
+        for channel in 0..self.channels {
+            let samples = self.interleaved[channel..].iter().step_by(self.channels);
+            for (sample, value) in self.buffer.chan_mut(channel).iter_mut().zip(samples) {
+                *sample = *value;
+            }
+        }
+        // Drops whatever the container marks as delay or padding
+        self.buffer
+            .trim(packet.trim_start() as usize, packet.trim_end() as usize);
+        Ok(())
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        if params.codec != CODEC_TYPE_OPUS {
+            return unsupported_error("opus: invalid codec type");
+        }
+        let Some(layout) = params.channels else {
+            return unsupported_error("opus: missing channel layout");
+        };
+        let channels = match layout.count() {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            // Surround files need libopus' multistream decoder
+            _ => return unsupported_error("opus: only mono and stereo are supported"),
+        };
+
+        let decoder = LibOpus::new(SampleRate::Hz48000, channels)
+            .map_err(|_| Error::Unsupported("opus: could not create decoder"))?;
+        // The header's output gain is in the same Q7.8 dB units libopus takes
+        if let Some(head) = params.extra_data.as_deref()
+            && let Some(gain) = head.get(HEAD_GAIN_OFFSET..HEAD_GAIN_OFFSET + 2)
+        {
+            let gain = i16::from_le_bytes([gain[0], gain[1]]);
+            decoder
+                .set_gain(i32::from(gain))
+                .map_err(|_| Error::Unsupported("opus: invalid output gain"))?;
+        }
+
+        let channels = layout.count();
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels,
+            interleaved: vec![0.0; MAX_PACKET_FRAMES * channels],
+            buffer: AudioBuffer::new(
+                MAX_PACKET_FRAMES as u64,
+                SignalSpec::new(SAMPLE_RATE, layout),
+            ),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Resetting only fails on a decoder libopus never handed out
+        let _ = decoder.reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        match self.decode_packet(packet) {
+            Ok(()) => Ok(self.buffer.as_audio_buffer_ref()),
+            Err(e) => {
+                self.buffer.clear();
+                Err(e)
+            }
+        }
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audiopus::Application;
+    use audiopus::coder::Encoder;
+    use symphonia::core::audio::Channels as Layout;
+
+    // 20 ms, the usual Opus frame
+    const FRAME: usize = 960;
+
+    fn params(layout: Layout) -> CodecParameters {
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_OPUS)
+            .with_sample_rate(SAMPLE_RATE)
+            .with_channels(layout);
+        params
+    }
+
+    fn encode_sine(frames: usize) -> Vec<Vec<u8>> {
+        let encoder =
+            Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Audio).expect("encoder");
+        let signal: Vec<f32> = (0..frames)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin() * 0.5)
+            .collect();
+        signal
+            .chunks_exact(FRAME)
+            .map(|chunk| {
+                let mut packet = vec![0; 4000];
+                let length = encoder.encode_float(chunk, &mut packet).expect("encode");
+                packet.truncate(length);
+                packet
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_whole_packets_and_trims_padding() {
+        let mut decoder = OpusDecoder::try_new(&params(Layout::FRONT_LEFT), &Default::default())
+            .expect("decoder");
+        let packets = encode_sine(FRAME * 10);
+
+        let mut decoded = Vec::new();
+        for (index, bytes) in packets.iter().enumerate() {
+            let mut packet = Packet::new_from_slice(0, (index * FRAME) as u64, FRAME as u64, bytes);
+            if index == 0 {
+                packet.trim_start = 312;
+            }
+            if index == packets.len() - 1 {
+                packet.trim_end = 100;
+            }
+            let buffer = decoder.decode(&packet).expect("decode");
+            let AudioBufferRef::F32(buffer) = buffer else {
+                panic!("Opus decodes to f32");
+            };
+            decoded.extend_from_slice(buffer.chan(0));
+        }
+
+        assert_eq!(decoded.len(), FRAME * 10 - 312 - 100);
+        // Past the codec's start-up the sine comes back at about its level
+        let peak = decoded[FRAME..]
+            .iter()
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((0.4..0.6).contains(&peak), "peak {}", peak);
+    }
+
+    #[test]
+    fn refuses_surround() {
+        let layout = Layout::FRONT_LEFT | Layout::FRONT_CENTRE | Layout::FRONT_RIGHT;
+        assert!(OpusDecoder::try_new(&params(layout), &Default::default()).is_err());
+    }
+}
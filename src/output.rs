@@ -1,22 +1,22 @@
 use pipewire as pw;
 use pw::{properties::properties, spa};
+use spa::param::format::{MediaSubtype, MediaType};
 use spa::pod::Pod;
-use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::audio_stream::AudioStream;
+use crate::engine::PlaybackBuffer;
 
 // Quantum size requested from PipeWire - controls how often the callback fires
 // and how many frames we process per callback. Set to 128 for low latency.
 // Note: PipeWire may allocate larger buffers, but we only fill this many frames
-// to keep position updates smooth in the TUI.
+// so audio is pulled from the engine's ring buffer in small, even steps.
 const QUANTUM_SIZE: usize = 128;
 
 pub struct PipewireStream {
     _thread: thread::JoinHandle<()>,
 }
 
-pub fn output_stream(audio_stream: Arc<Mutex<AudioStream>>) -> PipewireStream {
+pub fn output_stream(playback_buffer: PlaybackBuffer) -> PipewireStream {
     let thread = thread::spawn(move || {
         pw::init();
 
@@ -24,10 +24,8 @@ pub fn output_stream(audio_stream: Arc<Mutex<AudioStream>>) -> PipewireStream {
         let context = pw::context::ContextRc::new(&mainloop, None).expect("Failed to create context");
         let core = context.connect_rc(None).expect("Failed to connect to PipeWire");
 
-        let (sample_rate, channels) = {
-            let audio_stream_lock = audio_stream.lock().unwrap();
-            (audio_stream_lock.sample_rate as u32, audio_stream_lock.channels as u32)
-        };
+        let sample_rate = playback_buffer.sample_rate as u32;
+        let channels = playback_buffer.channels as u32;
 
         let stream = pw::stream::StreamBox::new(
             &core,
@@ -42,30 +40,50 @@ pub fn output_stream(audio_stream: Arc<Mutex<AudioStream>>) -> PipewireStream {
         .expect("Failed to create stream");
 
         let channels_usize = channels as usize;
+        // Allocated up front so the real time callback never allocates
+        let mut samples = vec![0.0f32; channels_usize];
 
         let _listener = stream
-            .add_local_listener_with_user_data(audio_stream)
-            .process(move |stream, user_data| {
+            .add_local_listener_with_user_data(playback_buffer)
+            .param_changed(|_, playback_buffer, id, param| {
+                // The engine resamples to whatever rate the graph settled on
+                let Some(param) = param else {
+                    return;
+                };
+                if id != pw::spa::param::ParamType::Format.as_raw() {
+                    return;
+                }
+                match spa::param::format_utils::parse_format(param) {
+                    Ok((MediaType::Audio, MediaSubtype::Raw)) => {}
+                    _ => return,
+                }
+
+                let mut negotiated = spa::param::audio::AudioInfoRaw::new();
+                if negotiated.parse(param).is_ok() {
+                    playback_buffer.set_output_rate(negotiated.rate());
+                }
+            })
+            .process(move |stream, playback_buffer| {
                 if let Some(mut buffer) = stream.dequeue_buffer() {
                     let datas = buffer.datas_mut();
                     if let Some(data) = datas.first_mut() {
                         if let Some(slice) = data.data() {
-                            const BYTES_PER_SAMPLE: usize = 2; // i16
+                            const BYTES_PER_SAMPLE: usize = 4; // f32
                             let stride = channels_usize * BYTES_PER_SAMPLE;
 
                             // PipeWire provides a large buffer (can be 24k+ frames)
                             let pipewire_buffer_frames = slice.len() / stride;
 
                             // We intentionally only fill QUANTUM_SIZE frames per callback
-                            // (not the full buffer) to keep the file position advancing in
-                            // small increments. This makes the TUI position display update
-                            // smoothly instead of jumping in large chunks.
+                            // (not the full buffer) so the engine's ring buffer drains in
+                            // small increments and stays topped up.
                             let frames_to_process = QUANTUM_SIZE.min(pipewire_buffer_frames);
 
-                            let mut reader_guard = user_data.lock().unwrap();
+                            // Nothing here may block: the engine thread owns the audio
+                            // stream and only hands us decoded frames through the ring.
                             for i in 0..frames_to_process {
-                                let samples = reader_guard.read_frame();
-                                for (j, &sample) in samples.iter().enumerate().take(channels_usize) {
+                                playback_buffer.read_frame(&mut samples);
+                                for (j, &sample) in samples.iter().enumerate() {
                                     let byte_offset = i * stride + j * BYTES_PER_SAMPLE;
                                     slice[byte_offset..byte_offset + BYTES_PER_SAMPLE]
                                         .copy_from_slice(&sample.to_le_bytes());
@@ -84,9 +102,10 @@ pub fn output_stream(audio_stream: Arc<Mutex<AudioStream>>) -> PipewireStream {
             .register()
             .expect("Failed to register listener");
 
+        // The rate is left unset so the stream runs at the graph rate and
+        // any conversion happens in the engine rather than in PipeWire
         let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-        audio_info.set_format(spa::param::audio::AudioFormat::S16LE);
-        audio_info.set_rate(sample_rate);
+        audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
         audio_info.set_channels(channels);
 
         let obj = pw::spa::pod::Object {
@@ -0,0 +1,151 @@
+use crate::stft::{self, FRAME_SIZE, HOP_SIZE, WINDOW_GAIN};
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::sync::Arc;
+
+// The output lags the input by one STFT frame (about 46ms at 44.1kHz) while
+// extraction is on
+const MIN_WIDTH: f32 = 0.05;
+
+/// Keeps only the parts of a stereo mix panned near a chosen position.
+///
+/// Each STFT bin gets a pan estimate from the balance of its left and right
+/// magnitudes, and is attenuated the further that lies from the target.
+pub struct PanExtract {
+    enabled: bool,
+    // Target position, from -1 (hard left) to 1 (hard right)
+    pan: f32,
+    // Distance from the target at which a bin is attenuated by half
+    width: f32,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    window: Vec<f32>,
+    // The last FRAME_SIZE input frames per channel
+    input: [Vec<f32>; 2],
+    // Overlap-add accumulators per channel; the first hop is finished
+    output: [Vec<f32>; 2],
+    hop_position: usize,
+    time_buffer: Vec<f32>,
+    bins: [Vec<Complex<f32>>; 2],
+}
+
+impl PanExtract {
+    pub fn new() -> Self {
+        let mut planner = RealFftPlanner::<f32>::new();
+        let forward = planner.plan_fft_forward(FRAME_SIZE);
+        let inverse = planner.plan_fft_inverse(FRAME_SIZE);
+        let window = stft::hann_window();
+        let bins = [forward.make_output_vec(), forward.make_output_vec()];
+
+        PanExtract {
+            enabled: false,
+            pan: 0.0,
+            width: 0.2,
+            forward,
+            inverse,
+            window,
+            input: [vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]],
+            output: [vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]],
+            hop_position: 0,
+            time_buffer: vec![0.0; FRAME_SIZE],
+            bins,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.reset();
+    }
+
+    pub fn move_pan(&mut self, delta: f32) {
+        self.pan = ((self.pan + delta) * 100.0).round().clamp(-100.0, 100.0) / 100.0;
+    }
+
+    pub fn change_width(&mut self, delta: f32) {
+        self.width = ((self.width + delta) * 100.0)
+            .round()
+            .clamp(MIN_WIDTH * 100.0, 100.0)
+            / 100.0;
+    }
+
+    pub fn description(&self) -> String {
+        if !self.enabled {
+            return "off".to_string();
+        }
+        let position = match (self.pan * 100.0).round() as i32 {
+            0 => "center".to_string(),
+            pan if pan < 0 => format!("L{}", -pan),
+            pan => format!("R{}", pan),
+        };
+        format!("{} width {:.0}%", position, self.width * 100.0)
+    }
+
+    /// Drops buffered audio, e.g. after a seek.
+    pub fn reset(&mut self) {
+        for buffer in self.input.iter_mut().chain(self.output.iter_mut()) {
+            buffer.fill(0.0);
+        }
+        self.hop_position = 0;
+    }
+
+    pub fn apply(&mut self, frame: &mut [f32]) {
+        if !self.enabled || frame.len() < 2 {
+            return;
+        }
+
+        let write_index = FRAME_SIZE - HOP_SIZE + self.hop_position;
+        for ((sample, input), output) in frame.iter_mut().zip(&mut self.input).zip(&self.output) {
+            input[write_index] = *sample;
+            *sample = output[self.hop_position];
+        }
+
+        self.hop_position += 1;
+        if self.hop_position == HOP_SIZE {
+            self.hop_position = 0;
+            self.process_frame();
+        }
+    }
+
+    fn process_frame(&mut self) {
+        for channel in 0..2 {
+            for (i, sample) in self.time_buffer.iter_mut().enumerate() {
+                *sample = self.input[channel][i] * self.window[i];
+            }
+            self.forward
+                .process(&mut self.time_buffer, &mut self.bins[channel])
+                .expect("FFT size mismatch");
+        }
+
+        let [left, right] = &mut self.bins;
+        for (left, right) in left.iter_mut().zip(right.iter_mut()) {
+            let left_magnitude = left.norm();
+            let right_magnitude = right.norm();
+            let total = left_magnitude + right_magnitude;
+            let gain = if total > 0.0 {
+                let pan = (right_magnitude - left_magnitude) / total;
+                let distance = (pan - self.pan) / self.width;
+                0.5f32.powf(distance * distance)
+            } else {
+                0.0
+            };
+            *left *= gain;
+            *right *= gain;
+        }
+
+        let scale = 1.0 / (FRAME_SIZE as f32 * WINDOW_GAIN);
+        for channel in 0..2 {
+            let output = &mut self.output[channel];
+            output.copy_within(HOP_SIZE.., 0);
+            output[FRAME_SIZE - HOP_SIZE..].fill(0.0);
+
+            self.inverse
+                .process(&mut self.bins[channel], &mut self.time_buffer)
+                .expect("FFT size mismatch");
+            for (i, sample) in self.time_buffer.iter().enumerate() {
+                output[i] += sample * self.window[i] * scale;
+            }
+
+            self.input[channel].copy_within(HOP_SIZE.., 0);
+        }
+    }
+}
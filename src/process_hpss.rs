@@ -0,0 +1,247 @@
+use crate::decoder;
+use crate::memory_decoder::MemoryDecoder;
+use crate::save_data::{Layer, LayerVersion, SongData};
+use crate::stft::{self, BINS, FRAME_SIZE, HOP_SIZE, WINDOW_GAIN};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use realfft::RealFftPlanner;
+use realfft::num_complex::Complex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+
+// Median filter lengths, across time for harmonics and across frequency for
+// percussion
+const HARMONIC_FRAMES: usize = 17;
+const PERCUSSIVE_BINS: usize = 17;
+
+struct Spectrum {
+    // One spectrum per channel
+    channels: Vec<Vec<Complex<f32>>>,
+    // Magnitude of the channel sum, used to build the masks
+    magnitude: Vec<f32>,
+}
+
+/// Splits the song into harmonic and percussive layers with median filtering
+/// of the spectrogram (HPSS) and saves both as wave files in the song
+/// directory.
+pub fn process(song_data: &SongData) -> Result<(), String> {
+    if song_data.layer_versions.len() == 2 {
+        println!("Layers already exist");
+        return Ok(());
+    }
+
+    let mut source = decoder::open(&song_data.original_file_path)?;
+    let spec = source.spec();
+    let audio = MemoryDecoder::from_decoder(source.as_mut())?;
+    let samples = audio.samples();
+    let channels = spec.channels;
+    let length = samples.len() / channels;
+
+    let wav_spec = WavSpec {
+        channels: channels as u16,
+        sample_rate: spec.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let harmonic_path = song_data.song_dir.join("harmonic.wav");
+    let percussive_path = song_data.song_dir.join("percussive.wav");
+    let mut harmonic_writer = create_writer(&harmonic_path, wav_spec)?;
+    let mut percussive_writer = create_writer(&percussive_path, wav_spec)?;
+
+    let mut planner = RealFftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(FRAME_SIZE);
+    let inverse = planner.plan_fft_inverse(FRAME_SIZE);
+    let window = stft::hann_window();
+
+    // Frame t starts at t * HOP_SIZE - FRAME_SIZE + HOP_SIZE, so the first
+    // frame already reaches sample 0 and the last one passes the end
+    let frame_count = (length + FRAME_SIZE - HOP_SIZE).div_ceil(HOP_SIZE);
+    let frame_start = |t: usize| (t * HOP_SIZE) as isize - (FRAME_SIZE - HOP_SIZE) as isize;
+
+    let mut analysis_buffer = vec![0.0; FRAME_SIZE];
+    let mut analyse = |t: usize| {
+        let start = frame_start(t);
+        let mut spectrum = Spectrum {
+            channels: Vec::with_capacity(channels),
+            magnitude: vec![0.0; BINS],
+        };
+        let mut mono = vec![Complex::default(); BINS];
+        for channel in 0..channels {
+            for (i, sample) in analysis_buffer.iter_mut().enumerate() {
+                let index = start + i as isize;
+                *sample = if index >= 0 && (index as usize) < length {
+                    samples[index as usize * channels + channel] * window[i]
+                } else {
+                    0.0
+                };
+            }
+            let mut bins = forward.make_output_vec();
+            forward
+                .process(&mut analysis_buffer, &mut bins)
+                .expect("FFT size mismatch");
+            for (sum, bin) in mono.iter_mut().zip(&bins) {
+                *sum += bin;
+            }
+            spectrum.channels.push(bins);
+        }
+        for (magnitude, bin) in spectrum.magnitude.iter_mut().zip(&mono) {
+            *magnitude = bin.norm();
+        }
+        spectrum
+    };
+
+    // Spectra for frames t - HARMONIC_FRAMES / 2 up to t + HARMONIC_FRAMES / 2
+    let half = HARMONIC_FRAMES / 2;
+    let mut spectra: VecDeque<Spectrum> = VecDeque::new();
+    let mut first_buffered = 0;
+    let mut next_to_analyse = 0;
+
+    let mut harmonic_output = vec![0.0; FRAME_SIZE * channels];
+    let mut percussive_output = vec![0.0; FRAME_SIZE * channels];
+    let mut median_buffer = Vec::with_capacity(HARMONIC_FRAMES.max(PERCUSSIVE_BINS));
+    let mut harmonic_mask = vec![0.0; BINS];
+    let mut percussive_mask = vec![0.0; BINS];
+    let mut bins = vec![Complex::default(); BINS];
+    let mut time_buffer = vec![0.0; FRAME_SIZE];
+
+    for t in 0..frame_count {
+        while next_to_analyse < frame_count && next_to_analyse <= t + half {
+            spectra.push_back(analyse(next_to_analyse));
+            next_to_analyse += 1;
+        }
+        while first_buffered + half < t {
+            spectra.pop_front();
+            first_buffered += 1;
+        }
+
+        let current = &spectra[t - first_buffered];
+        for bin in 0..BINS {
+            median_buffer.clear();
+            median_buffer.extend(spectra.iter().map(|s| s.magnitude[bin]));
+            let harmonic = median(&mut median_buffer);
+
+            median_buffer.clear();
+            let low = bin.saturating_sub(PERCUSSIVE_BINS / 2);
+            let high = (bin + PERCUSSIVE_BINS / 2).min(BINS - 1);
+            median_buffer.extend_from_slice(&current.magnitude[low..=high]);
+            let percussive = median(&mut median_buffer);
+
+            // Wiener style soft mask; the percussive mask is its complement
+            let harmonic_power = harmonic * harmonic;
+            let percussive_power = percussive * percussive;
+            let total = harmonic_power + percussive_power;
+            harmonic_mask[bin] = if total > 0.0 {
+                harmonic_power / total
+            } else {
+                0.5
+            };
+            percussive_mask[bin] = 1.0 - harmonic_mask[bin];
+        }
+
+        for channel in 0..channels {
+            let layers = [
+                (&harmonic_mask, &mut harmonic_output),
+                (&percussive_mask, &mut percussive_output),
+            ];
+            for (mask, output) in layers {
+                for ((value, bin), mask) in
+                    bins.iter_mut().zip(&current.channels[channel]).zip(mask)
+                {
+                    *value = bin * mask;
+                }
+                // Rounding can leave a tiny imaginary part on the DC and
+                // Nyquist bins, which the inverse transform rejects
+                bins[0].im = 0.0;
+                bins[BINS - 1].im = 0.0;
+                inverse
+                    .process(&mut bins, &mut time_buffer)
+                    .expect("FFT size mismatch");
+                for (i, sample) in time_buffer.iter().enumerate() {
+                    output[i * channels + channel] +=
+                        sample * window[i] / (FRAME_SIZE as f32 * WINDOW_GAIN);
+                }
+            }
+        }
+
+        // The first hop of the accumulators has had every frame added to it
+        let start = frame_start(t);
+        write_hop(
+            &mut harmonic_writer,
+            &mut harmonic_output,
+            start,
+            length,
+            channels,
+        )?;
+        write_hop(
+            &mut percussive_writer,
+            &mut percussive_output,
+            start,
+            length,
+            channels,
+        )?;
+    }
+
+    finish_writer(harmonic_writer)?;
+    finish_writer(percussive_writer)?;
+
+    song_data.save_layer_versions(vec![
+        LayerVersion {
+            layer: Layer::Harmonic,
+            file_path: harmonic_path,
+        },
+        LayerVersion {
+            layer: Layer::Percussive,
+            file_path: percussive_path,
+        },
+    ]);
+
+    Ok(())
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    let middle = values.len() / 2;
+    *values
+        .select_nth_unstable_by(middle, |a, b| a.total_cmp(b))
+        .1
+}
+
+fn create_writer(
+    path: &std::path::Path,
+    spec: WavSpec,
+) -> Result<WavWriter<BufWriter<File>>, String> {
+    WavWriter::create(path, spec).map_err(|e| format!("Could not create layer file: {}", e))
+}
+
+fn finish_writer(writer: WavWriter<BufWriter<File>>) -> Result<(), String> {
+    writer
+        .finalize()
+        .map_err(|e| format!("Could not finish layer file: {}", e))
+}
+
+// Writes the finished first hop of an overlap-add accumulator, skipping the
+// padding before the start and after the end of the song, and shifts it along
+fn write_hop(
+    writer: &mut WavWriter<BufWriter<File>>,
+    output: &mut [f32],
+    start: isize,
+    length: usize,
+    channels: usize,
+) -> Result<(), String> {
+    for i in 0..HOP_SIZE {
+        let index = start + i as isize;
+        if index < 0 || index as usize >= length {
+            continue;
+        }
+        for channel in 0..channels {
+            writer
+                .write_sample(output[i * channels + channel])
+                .map_err(|e| format!("Could not write layer file: {}", e))?;
+        }
+    }
+
+    let hop_samples = HOP_SIZE * channels;
+    output.copy_within(hop_samples.., 0);
+    let tail = output.len() - hop_samples;
+    output[tail..].fill(0.0);
+    Ok(())
+}
@@ -1,30 +1,102 @@
-use crate::save_data::SongData;
+use crate::align;
+use crate::decoder;
+use crate::save_data::{SongData, SpeedVersion};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::path::{Path, PathBuf};
 
 pub fn process(song_data: &SongData, speed: f32) -> Result<(), String> {
     let output_path = song_data.song_dir.join(format!("speed_{:.2}.wav", speed));
 
-    if song_data
+    if let Some(existing) = song_data
         .speed_versions
         .iter()
-        .any(|v| v.speed == speed && v.file_path == output_path)
+        .find(|v| v.speed == speed && v.file_path == output_path)
     {
         println!("Speed version already exists: {}", output_path.display());
+        // Versions processed before alignment existed get their time map now
+        if existing.time_map.is_empty() {
+            save_aligned(song_data, output_path, speed);
+        }
         return Ok(());
     }
 
+    // rubberband only reads what libsndfile understands, so anything other
+    // than a wave file is decoded to a temporary wave file first
+    let is_wave = song_data
+        .original_file_path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("wav"));
+    let input_path = if is_wave {
+        song_data.original_file_path.clone()
+    } else {
+        let temp_path = std::env::temp_dir().join(format!("transcriber_{}.wav", song_data.hash));
+        decode_to_wave(&song_data.original_file_path, &temp_path)?;
+        temp_path
+    };
+
     let status = std::process::Command::new("rubberband-r3")
         .arg("-t")
         .arg(format!("{:.2}", speed))
-        .arg(&song_data.original_file_path)
+        .arg(&input_path)
         .arg(&output_path)
-        .status()
-        .map_err(|e| format!("Failed to run rubberband: {}", e))?;
+        .status();
+
+    if !is_wave {
+        std::fs::remove_file(&input_path).ok();
+    }
+
+    let status = status.map_err(|e| format!("Failed to run rubberband: {}", e))?;
 
     if !status.success() {
         return Err("rubberband failed to process the file".to_string());
     }
 
-    song_data.save_new_speed_version(output_path, speed);
+    save_aligned(song_data, output_path, speed);
 
     return Ok(());
 }
+
+// rubberband does not keep exactly to the requested speed, so the version is
+// measured against the original to find where each point of it ended up
+fn save_aligned(song_data: &SongData, file_path: PathBuf, speed: f32) {
+    println!("Aligning {} with the original", file_path.display());
+    let time_map =
+        align::align(&song_data.original_file_path, &file_path, speed).unwrap_or_else(|e| {
+            eprintln!(
+                "Could not align speed version, using its nominal speed: {}",
+                e
+            );
+            Vec::new()
+        });
+    song_data.save_speed_version(SpeedVersion {
+        speed,
+        file_path,
+        time_map,
+    });
+}
+
+fn decode_to_wave(source: &Path, destination: &Path) -> Result<(), String> {
+    let mut decoder = decoder::open(source)?;
+    let source_spec = decoder.spec();
+    let spec = WavSpec {
+        channels: source_spec.channels as u16,
+        sample_rate: source_spec.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(destination, spec)
+        .map_err(|e| format!("Could not create temporary wave file: {}", e))?;
+
+    let mut frame = vec![0.0; source_spec.channels];
+    while decoder.read_frame(&mut frame) {
+        for &sample in &frame {
+            writer
+                .write_sample(sample)
+                .map_err(|e| format!("Could not write temporary wave file: {}", e))?;
+        }
+    }
+
+    writer
+        .finalize()
+        .map_err(|e| format!("Could not finish temporary wave file: {}", e))
+}
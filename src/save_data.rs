@@ -4,10 +4,83 @@ use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct SpeedVersion {
     pub speed: f32,
     pub file_path: PathBuf,
+    /// Measured positions of the original in this version, in order
+    #[serde(default)]
+    pub time_map: Vec<TimePoint>,
+}
+
+/// A point in the original and where it was found in a speed version, both
+/// in seconds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct TimePoint {
+    pub original: f64,
+    pub version: f64,
+}
+
+impl SpeedVersion {
+    /// Where `time` seconds into the original falls in this version.
+    pub fn version_time(&self, time: f64) -> f64 {
+        map_time(&self.time_map, time, self.speed as f64, |p| {
+            (p.original, p.version)
+        })
+    }
+
+    /// Where `time` seconds into this version falls in the original.
+    pub fn original_time(&self, time: f64) -> f64 {
+        map_time(&self.time_map, time, 1.0 / self.speed as f64, |p| {
+            (p.version, p.original)
+        })
+    }
+}
+
+/// Checks that a time map rises in both the original and the version, which
+/// interpolating and inverting it rely on.
+pub fn validate_time_map(map: &[TimePoint]) -> Result<(), String> {
+    // Written so NaN fails too
+    let rises = |a: f64, b: f64| a < b;
+    match map.windows(2).find(|pair| {
+        !rises(pair[0].original, pair[1].original) || !rises(pair[0].version, pair[1].version)
+    }) {
+        Some(pair) => Err(format!(
+            "time map does not rise at {:.2}s of the original",
+            pair[1].original
+        )),
+        None => Ok(()),
+    }
+}
+
+// Interpolates between the points of a time map, and carries on at the
+// nominal rate before the first point and after the last
+fn map_time(
+    map: &[TimePoint],
+    time: f64,
+    rate: f64,
+    axes: impl Fn(&TimePoint) -> (f64, f64),
+) -> f64 {
+    let index = map.partition_point(|p| axes(p).0 <= time);
+    let before = index.checked_sub(1).map(|i| axes(&map[i]));
+    let after = map.get(index).map(&axes);
+    match (before, after) {
+        (Some((x0, y0)), Some((x1, y1))) => y0 + (time - x0) / (x1 - x0) * (y1 - y0),
+        (Some((x, y)), None) | (None, Some((x, y))) => y + (time - x) * rate,
+        (None, None) => time * rate,
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Harmonic,
+    Percussive,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LayerVersion {
+    pub layer: Layer,
+    pub file_path: PathBuf,
 }
 
 pub struct SongData {
@@ -15,10 +88,11 @@ pub struct SongData {
     pub hash: String,
     pub song_dir: PathBuf,
     pub speed_versions: Vec<SpeedVersion>,
+    pub layer_versions: Vec<LayerVersion>,
 }
 
 impl SongData {
-    pub fn from_wave_file(file_path: &str) -> Self {
+    pub fn from_file(file_path: &str) -> Self {
         // First, read and hash the entire file
         let mut hasher = Hasher::new();
         let mut file = std::fs::File::open(file_path).expect("Could not open file");
@@ -40,6 +114,7 @@ impl SongData {
         let mut versions = vec![SpeedVersion {
             speed: 1.0,
             file_path: PathBuf::from(file_path),
+            time_map: Vec::new(),
         }];
 
         if speed_versions_path.exists() {
@@ -48,28 +123,155 @@ impl SongData {
                     serde_json::from_str::<Vec<SpeedVersion>>(&speed_versions_str)
                 {
                     // Only add versions that still exist on disk
-                    versions.extend(loaded_versions.into_iter().filter(|v| v.file_path.exists()));
+                    versions.extend(
+                        loaded_versions
+                            .into_iter()
+                            .filter(|v| v.file_path.exists())
+                            .map(Self::checked_version),
+                    );
                 }
             }
         }
 
+        let layers_path = song_dir.join("layers.json");
+        let layer_versions = fs::read_to_string(&layers_path)
+            .ok()
+            .and_then(|layers_str| serde_json::from_str::<Vec<LayerVersion>>(&layers_str).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|v| v.file_path.exists())
+            .collect();
+
         SongData {
             original_file_path: PathBuf::from(file_path),
             hash,
             song_dir,
             speed_versions: versions,
+            layer_versions,
+        }
+    }
+
+    // A version whose time map cannot be used plays at its nominal speed
+    fn checked_version(mut version: SpeedVersion) -> SpeedVersion {
+        if let Err(e) = validate_time_map(&version.time_map) {
+            eprintln!(
+                "Ignoring the time map of {}: {}",
+                version.file_path.display(),
+                e
+            );
+            version.time_map.clear();
         }
+        version
     }
 
-    pub fn save_new_speed_version(&self, file_path: PathBuf, speed: f32) {
+    /// Saves a new speed version, or replaces the one with the same file.
+    pub fn save_speed_version(&self, version: SpeedVersion) {
         let speed_versions_path = self.song_dir.join("speed_versions.json");
 
         let mut versions = self.speed_versions.clone();
 
-        versions.extend([SpeedVersion { speed, file_path }]);
+        versions.retain(|v| v.file_path != version.file_path);
+        versions.push(version);
 
         let stringified = serde_json::to_string_pretty(&versions).unwrap();
         fs::write(speed_versions_path, stringified).expect("could not update versions");
     }
+
+    pub fn save_layer_versions(&self, versions: Vec<LayerVersion>) {
+        let layers_path = self.song_dir.join("layers.json");
+        let stringified = serde_json::to_string_pretty(&versions).unwrap();
+        fs::write(layers_path, stringified).expect("could not update layers");
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(speed: f32, time_map: Vec<TimePoint>) -> SpeedVersion {
+        SpeedVersion {
+            speed,
+            file_path: PathBuf::new(),
+            time_map,
+        }
+    }
+
+    #[test]
+    fn maps_at_nominal_speed_without_time_map() {
+        let version = version(1.25, Vec::new());
+        assert_eq!(version.version_time(4.0), 5.0);
+        assert_eq!(version.original_time(5.0), 4.0);
+        for time in [0.0, 0.3, 17.1, 200.0] {
+            assert!((version.original_time(version.version_time(time)) - time).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_time_map() {
+        let version = version(
+            2.0,
+            vec![
+                TimePoint {
+                    original: 5.0,
+                    version: 10.02,
+                },
+                TimePoint {
+                    original: 10.0,
+                    version: 20.01,
+                },
+            ],
+        );
+        for time in [0.0, 2.5, 5.0, 7.3, 10.0, 31.0] {
+            let back = version.original_time(version.version_time(time));
+            assert!(
+                (back - time).abs() < 1e-12,
+                "{} came back as {}",
+                time,
+                back
+            );
+        }
+    }
+
+    fn point(original: f64, version: f64) -> TimePoint {
+        TimePoint { original, version }
+    }
+
+    #[test]
+    fn interpolates_between_points_and_extrapolates_at_nominal_rate() {
+        let map = [point(2.0, 4.1), point(4.0, 8.3)];
+        let forward = |time| map_time(&map, time, 2.0, |p| (p.original, p.version));
+        assert!((forward(3.0) - 6.2).abs() < 1e-12);
+        assert!((forward(2.0) - 4.1).abs() < 1e-12);
+        assert!((forward(1.0) - 2.1).abs() < 1e-12);
+        assert!((forward(5.0) - 10.3).abs() < 1e-12);
+        assert_eq!(map_time(&[], 3.0, 2.0, |p| (p.original, p.version)), 6.0);
+    }
+
+    #[test]
+    fn time_map_edge_cases() {
+        // Without points the version runs at its nominal speed
+        let empty = version(2.0, Vec::new());
+        assert_eq!(empty.version_time(3.0), 6.0);
+        assert_eq!(empty.original_time(6.0), 3.0);
+        assert!(validate_time_map(&[]).is_ok());
+
+        // Before the first point and after the last, the nominal speed
+        // carries on from that point rather than from zero
+        let version = version(2.0, vec![point(5.0, 10.2), point(10.0, 20.1)]);
+        assert!((version.version_time(1.0) - 2.2).abs() < 1e-12);
+        assert!((version.original_time(2.2) - 1.0).abs() < 1e-12);
+        assert!((version.version_time(12.0) - 24.1).abs() < 1e-12);
+        assert!((version.original_time(24.1) - 12.0).abs() < 1e-12);
+        assert!(validate_time_map(&version.time_map).is_ok());
+
+        // Maps that stall or go back in either column cannot be inverted
+        for map in [
+            [point(5.0, 10.2), point(4.0, 20.1)],
+            [point(5.0, 10.2), point(10.0, 9.0)],
+            [point(5.0, 10.2), point(5.0, 20.1)],
+            [point(5.0, 10.2), point(10.0, f64::NAN)],
+        ] {
+            assert!(validate_time_map(&map).is_err(), "{:?}", map);
+        }
+    }
+}
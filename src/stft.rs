@@ -0,0 +1,16 @@
+// Frame and hop shared by the STFT effects. A hop of a quarter frame makes
+// the squared Hann windows sum to a constant, so windowing both before and
+// after the FFT adds back up to the input.
+pub const FRAME_SIZE: usize = 2048;
+pub const HOP_SIZE: usize = FRAME_SIZE / 4;
+/// Sum of the squared Hann windows at a hop of a quarter frame
+pub const WINDOW_GAIN: f32 = 1.5;
+/// Bins in the spectrum of one frame
+pub const BINS: usize = FRAME_SIZE / 2 + 1;
+
+/// A periodic Hann window of `FRAME_SIZE` samples.
+pub fn hann_window() -> Vec<f32> {
+    (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FRAME_SIZE as f32).cos())
+        .collect()
+}
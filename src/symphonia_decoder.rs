@@ -0,0 +1,224 @@
+use crate::decoder::{AudioSpec, Decoder};
+use crate::opus_decoder::{self, OpusDecoder};
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{self, CODEC_TYPE_OPUS, CodecRegistry, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Decodes compressed formats (FLAC, MP3, Ogg Vorbis, Ogg Opus) through symphonia.
+pub struct SymphoniaDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    channels: usize,
+    sample_rate: usize,
+    total_frames: u64,
+    // Leading frames the codec emits that the container leaves untrimmed
+    start_offset: u64,
+    // Frames decoded ahead of a seek target to let the codec settle
+    pre_roll: u64,
+    samples: Vec<f32>,
+    sample_index: usize,
+    // Frames still to drop before the start of the track or a seek target
+    frames_to_skip: u64,
+    position: u64,
+    finished: bool,
+}
+
+impl SymphoniaDecoder {
+    /// Symphonia's own codecs plus Opus, which it can demux but not decode.
+    fn codecs() -> &'static CodecRegistry {
+        static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+        CODECS.get_or_init(|| {
+            let mut codecs = CodecRegistry::new();
+            symphonia::default::register_enabled_codecs(&mut codecs);
+            codecs.register_all::<OpusDecoder>();
+            codecs
+        })
+    }
+
+    fn count_frames(format: &mut dyn FormatReader, track_id: u32) -> Result<u64, String> {
+        let mut frames = 0;
+        while let Ok(packet) = format.next_packet() {
+            if packet.track_id() == track_id {
+                frames += packet.dur();
+            }
+        }
+
+        format
+            .seek(SeekMode::Coarse, SeekTo::TimeStamp { ts: 0, track_id })
+            .map_err(|e| format!("Could not rewind after measuring length: {}", e))?;
+        Ok(frames)
+    }
+
+    fn next_frame(&mut self, frame: &mut [f32]) -> bool {
+        if self.finished {
+            return false;
+        }
+
+        while self.sample_index >= self.samples.len() || self.frames_to_skip > 0 {
+            if self.sample_index >= self.samples.len() && !self.decode_packet() {
+                return false;
+            }
+            if self.frames_to_skip > 0 && self.sample_index < self.samples.len() {
+                self.sample_index += self.channels;
+                self.frames_to_skip -= 1;
+            }
+        }
+
+        frame.copy_from_slice(&self.samples[self.sample_index..self.sample_index + self.channels]);
+        self.sample_index += self.channels;
+        self.position += 1;
+        true
+    }
+
+    fn decode_packet(&mut self) -> bool {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => return false,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let mut buffer =
+                        SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                    buffer.copy_interleaved_ref(decoded);
+                    self.samples.clear();
+                    self.samples.extend_from_slice(buffer.samples());
+                    self.sample_index = 0;
+                    return true;
+                }
+                // Corrupt packets are skipped rather than ending playback
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+impl Decoder for SymphoniaDecoder {
+    fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open file: {}", e))?;
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        let format_options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe()
+            .format(&hint, stream, &format_options, &MetadataOptions::default())
+            .map_err(|e| format!("Unsupported audio file: {}", e))?;
+        let mut format = probed.format;
+
+        let track = format.default_track().ok_or("Audio file has no tracks")?;
+        let params = track.codec_params.clone();
+        let channels = params
+            .channels
+            .ok_or("Audio track has no channel layout")?
+            .count();
+        let sample_rate = params.sample_rate.ok_or("Audio track has no sample rate")? as usize;
+        let track_id = track.id;
+        let (start_offset, pre_roll) = if params.codec == CODEC_TYPE_OPUS {
+            (opus_decoder::pre_skip(&params), opus_decoder::SEEK_PRE_ROLL)
+        } else {
+            (0, 0)
+        };
+        let total_frames = match params.n_frames {
+            Some(frames) => frames,
+            // MP3s without a Xing/Info header do not state their length
+            None => Self::count_frames(format.as_mut(), track_id)?,
+        }
+        .saturating_sub(start_offset);
+
+        let decoder = Self::codecs()
+            .make(&params, &DecoderOptions::default())
+            .map_err(|e| format!("Unsupported codec: {}", e))?;
+
+        Ok(SymphoniaDecoder {
+            format,
+            decoder,
+            track_id,
+            channels,
+            sample_rate,
+            total_frames,
+            start_offset,
+            pre_roll,
+            samples: Vec::new(),
+            sample_index: 0,
+            frames_to_skip: start_offset,
+            position: 0,
+            finished: false,
+        })
+    }
+
+    fn spec(&self) -> AudioSpec {
+        AudioSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    fn duration(&self) -> u64 {
+        self.total_frames
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let mut frames = 0;
+        for frame in buffer.chunks_exact_mut(self.channels) {
+            if !self.next_frame(frame) {
+                break;
+            }
+            frames += 1;
+        }
+        frames
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.samples.clear();
+        self.sample_index = 0;
+        self.frames_to_skip = 0;
+
+        // Containers refuse to seek to the very end, so park there instead
+        self.finished = frame >= self.total_frames;
+        if self.finished {
+            self.position = self.total_frames;
+            return Ok(());
+        }
+
+        let target = frame + self.start_offset;
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: target.saturating_sub(self.pre_roll),
+                    track_id: self.track_id,
+                },
+            )
+            .map_err(|e| format!("Could not seek: {}", e))?;
+
+        self.decoder.reset();
+        self.frames_to_skip = target.saturating_sub(seeked.actual_ts);
+        self.position = frame;
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+// Length of each overlap-added segment, in seconds
+const WINDOW_SECONDS: f64 = 0.04;
+// How far either side of the nominal position to look for a matching segment
+const SEARCH_SECONDS: f64 = 0.01;
+// Stride of the coarse pass of the similarity search, refined afterwards
+const COARSE_STRIDE: usize = 4;
+
+/// Real time tempo change without pitch change using WSOLA (waveform
+/// similarity overlap-add).
+///
+/// Segments of the input are overlap-added at a fixed output hop, while the
+/// input advances by `tempo` times that hop. Each segment is nudged within a
+/// small search range to the position that best continues the previous one,
+/// which keeps periodic waveforms in phase across the seams.
+pub struct TimeStretch {
+    channels: usize,
+    tempo: f64,
+    window: usize,
+    hop: usize,
+    search: usize,
+    window_shape: Vec<f32>,
+    // Buffered input frames, interleaved; input[0] is frame `input_offset`
+    input: Vec<f32>,
+    input_offset: usize,
+    // Absolute frame where the next segment would start without searching
+    nominal_position: f64,
+    // Absolute frame where the previous segment started
+    previous_position: usize,
+    // Overlap-add accumulator covering one window of output
+    accumulator: Vec<f32>,
+    // Finished output frames, interleaved
+    ready: Vec<f32>,
+    ready_index: usize,
+    active: bool,
+}
+
+impl TimeStretch {
+    pub fn new(channels: usize, sample_rate: usize) -> Self {
+        let hop = ((sample_rate as f64 * WINDOW_SECONDS) as usize / 2).max(1);
+        let window = hop * 2;
+        let window_shape = (0..window)
+            .map(|i| {
+                let phase = std::f64::consts::PI * 2.0 * i as f64 / window as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+
+        TimeStretch {
+            channels,
+            tempo: 1.0,
+            window,
+            hop,
+            search: (sample_rate as f64 * SEARCH_SECONDS) as usize,
+            window_shape,
+            input: Vec::new(),
+            input_offset: 0,
+            nominal_position: 0.0,
+            previous_position: 0,
+            accumulator: vec![0.0; window * channels],
+            ready: Vec::new(),
+            ready_index: 0,
+            active: false,
+        }
+    }
+
+    /// Sets the playback tempo, where 0.5 plays at half speed.
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo;
+    }
+
+    /// Drops all buffered audio, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.input_offset = 0;
+        self.nominal_position = 0.0;
+        self.previous_position = 0;
+        self.accumulator.fill(0.0);
+        self.ready.clear();
+        self.ready_index = 0;
+        self.active = false;
+    }
+
+    /// Produces one output frame, pulling input frames as needed.
+    pub fn next_frame(&mut self, frame: &mut [f32], source: &mut dyn FnMut(&mut [f32])) {
+        // Until the tempo is first changed audio passes through untouched.
+        // Once running we keep stretching at 1.0 too, which reconstructs the
+        // input exactly, so returning to normal speed does not skip audio.
+        if !self.active {
+            if self.tempo == 1.0 {
+                source(frame);
+                return;
+            }
+            self.active = true;
+            self.start(source);
+        }
+
+        if self.ready_index >= self.ready.len() {
+            self.process_segment(source);
+        }
+
+        let start = self.ready_index;
+        frame.copy_from_slice(&self.ready[start..start + self.channels]);
+        self.ready_index += self.channels;
+    }
+
+    fn start(&mut self, source: &mut dyn FnMut(&mut [f32])) {
+        // Prime the buffer with silence before the first segment so the
+        // search has room to look backwards
+        self.input = vec![0.0; self.search * self.channels];
+        self.input_offset = 0;
+        self.nominal_position = self.search as f64;
+        self.previous_position = self.search;
+        self.fill_input(self.search + self.window + self.search, source);
+
+        // The first hop is passed through unwindowed, as if a previous
+        // segment had faded out under it, so engaging the stretch is seamless
+        let start = self.search * self.channels;
+        let hop_samples = self.hop * self.channels;
+        self.accumulator[..hop_samples].copy_from_slice(&self.input[start..start + hop_samples]);
+        for (i, weight) in self.window_shape.iter().enumerate().skip(self.hop) {
+            for channel in 0..self.channels {
+                let index = i * self.channels + channel;
+                self.accumulator[index] = self.input[start + index] * weight;
+            }
+        }
+        self.nominal_position += self.hop as f64 * self.tempo;
+        self.emit_hop();
+    }
+
+    fn process_segment(&mut self, source: &mut dyn FnMut(&mut [f32])) {
+        let nominal = self.nominal_position.round() as usize;
+        let search_start = nominal.saturating_sub(self.search).max(self.input_offset);
+        let natural = self.previous_position + self.hop;
+        let needed = (nominal + self.search + self.window).max(natural + self.hop);
+        self.fill_input(needed, source);
+
+        let position = self.best_position(nominal, search_start, nominal + self.search, natural);
+        self.overlap_add(position);
+        self.previous_position = position;
+        self.nominal_position += self.hop as f64 * self.tempo;
+        self.emit_hop();
+        self.trim_input();
+    }
+
+    fn fill_input(&mut self, until: usize, source: &mut dyn FnMut(&mut [f32])) {
+        let mut frame = vec![0.0; self.channels];
+        while self.input_offset + self.input.len() / self.channels < until {
+            source(&mut frame);
+            self.input.extend_from_slice(&frame);
+        }
+    }
+
+    /// Mono sample at an absolute frame position.
+    fn mono(&self, position: usize) -> f32 {
+        let start = (position - self.input_offset) * self.channels;
+        self.input[start..start + self.channels].iter().sum()
+    }
+
+    // Normalised cross correlation between the overlap region of a candidate
+    // and the natural continuation of the previous segment
+    fn similarity(&self, candidate: usize, natural: usize, stride: usize) -> f32 {
+        let mut correlation = 0.0;
+        let mut energy = 0.0;
+        for i in (0..self.hop).step_by(stride) {
+            let x = self.mono(candidate + i);
+            correlation += x * self.mono(natural + i);
+            energy += x * x;
+        }
+        correlation / (energy + 1e-9).sqrt()
+    }
+
+    fn best_position(&self, nominal: usize, from: usize, to: usize, natural: usize) -> usize {
+        // Ties go to the nominal position, which is scored first
+        let mut best = nominal;
+        let mut best_score = self.similarity(nominal, natural, COARSE_STRIDE);
+
+        // Coarse pass on a grid through the nominal position, then refine
+        let mut candidate = nominal - (nominal - from) / COARSE_STRIDE * COARSE_STRIDE;
+        while candidate <= to {
+            let score = self.similarity(candidate, natural, COARSE_STRIDE);
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+            candidate += COARSE_STRIDE;
+        }
+
+        let coarse_best = best;
+        best_score = self.similarity(coarse_best, natural, 1);
+        let refine_from = coarse_best.saturating_sub(COARSE_STRIDE - 1).max(from);
+        let refine_to = (coarse_best + COARSE_STRIDE - 1).min(to);
+        for candidate in refine_from..=refine_to {
+            let score = self.similarity(candidate, natural, 1);
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+
+    fn overlap_add(&mut self, position: usize) {
+        let start = (position - self.input_offset) * self.channels;
+        for (i, weight) in self.window_shape.iter().enumerate() {
+            for channel in 0..self.channels {
+                let index = i * self.channels + channel;
+                self.accumulator[index] += self.input[start + index] * weight;
+            }
+        }
+    }
+
+    fn emit_hop(&mut self) {
+        let hop_samples = self.hop * self.channels;
+        self.ready.clear();
+        self.ready
+            .extend_from_slice(&self.accumulator[..hop_samples]);
+        self.ready_index = 0;
+
+        self.accumulator.copy_within(hop_samples.., 0);
+        let tail = self.accumulator.len() - hop_samples;
+        self.accumulator[tail..].fill(0.0);
+    }
+
+    fn trim_input(&mut self) {
+        // Keep everything the next search or continuation could still read
+        let nominal = self.nominal_position.round() as usize;
+        let keep_from = nominal
+            .saturating_sub(self.search)
+            .min(self.previous_position + self.hop);
+        if keep_from > self.input_offset {
+            let drop = (keep_from - self.input_offset) * self.channels;
+            self.input.drain(..drop);
+            self.input_offset = keep_from;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 48000;
+
+    #[test]
+    fn output_length_follows_the_tempo() {
+        for tempo in [0.5, 0.8, 1.0, 1.5] {
+            let mut stretch = TimeStretch::new(2, RATE);
+            stretch.set_tempo(tempo);
+            let mut consumed = 0;
+            let mut source = |frame: &mut [f32]| {
+                frame.fill((consumed as f32 * 0.05).sin());
+                consumed += 1;
+            };
+
+            let produced = RATE * 4;
+            let mut frame = [0.0; 2];
+            for _ in 0..produced {
+                stretch.next_frame(&mut frame, &mut source);
+            }
+
+            // Input is read ahead by up to a window and the search range
+            let expected = produced as f64 * tempo;
+            let slack = (RATE as f64 * (WINDOW_SECONDS + 2.0 * SEARCH_SECONDS)) as usize;
+            assert!(
+                consumed as f64 >= expected && consumed <= expected as usize + slack,
+                "tempo {} consumed {} for {}",
+                tempo,
+                consumed,
+                produced
+            );
+        }
+    }
+}
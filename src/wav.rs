@@ -0,0 +1,386 @@
+use crate::decoder::{AudioSpec, Decoder};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    Int,
+    Float,
+}
+
+/// Sample format and location of the PCM payload inside a RIFF/WAVE file.
+#[derive(Clone, Copy, Debug)]
+pub struct WavLayout {
+    pub data_offset: u64,
+    pub data_len: u64,
+    pub channels: usize,
+    pub sample_rate: usize,
+    /// Size of one sample container, which may be wider than the valid bits
+    /// (e.g. 24 bit audio stored in 32 bit words).
+    pub bytes_per_sample: usize,
+    pub sample_format: SampleFormat,
+}
+
+impl WavLayout {
+    pub fn frame_size(&self) -> usize {
+        self.channels * self.bytes_per_sample
+    }
+
+    /// Decodes one interleaved frame of raw bytes into samples in [-1.0, 1.0].
+    pub fn decode_frame(&self, bytes: &[u8], frame: &mut [f32]) {
+        for (sample, raw) in frame
+            .iter_mut()
+            .zip(bytes.chunks_exact(self.bytes_per_sample))
+        {
+            *sample = self.decode_sample(raw);
+        }
+    }
+
+    fn decode_sample(&self, raw: &[u8]) -> f32 {
+        match (self.sample_format, self.bytes_per_sample) {
+            (SampleFormat::Float, 4) => f32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+            (SampleFormat::Float, 8) => f64::from_le_bytes([
+                raw[0], raw[1], raw[2], raw[3], raw[4], raw[5], raw[6], raw[7],
+            ]) as f32,
+            // 8 bit wave data is unsigned
+            (SampleFormat::Int, 1) => (raw[0] as f32 - 128.0) / 128.0,
+            (SampleFormat::Int, 2) => i16::from_le_bytes([raw[0], raw[1]]) as f32 / 32768.0,
+            (SampleFormat::Int, 3) => {
+                // Place the 24 bits in the top of an i32 so the sign extends
+                i32::from_le_bytes([0, raw[0], raw[1], raw[2]]) as f32 / 2147483648.0
+            }
+            (SampleFormat::Int, 4) => {
+                i32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f32 / 2147483648.0
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// Walks the RIFF chunk list, reading the `fmt ` chunk and stopping at the
+/// `data` chunk.
+///
+/// Any other chunk (`LIST`, `fact`, `bext`, `JUNK`, ...) is skipped,
+/// honouring the pad byte that follows odd sized chunks.
+pub fn read_layout(path: &Path) -> Result<WavLayout, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+    let file_len = file
+        .metadata()
+        .map_err(|e| format!("Could not read metadata: {}", e))?
+        .len();
+    parse_layout(&mut BufReader::new(file), file_len)
+}
+
+fn parse_layout(reader: &mut (impl Read + Seek), file_len: u64) -> Result<WavLayout, String> {
+    let mut riff_header = [0u8; 12];
+    reader
+        .read_exact(&mut riff_header)
+        .map_err(|_| "File is too short to be a wave file".to_string())?;
+    if &riff_header[0..4] != b"RIFF" || &riff_header[8..12] != b"WAVE" {
+        return Err("File is not a RIFF/WAVE file".to_string());
+    }
+
+    let mut format: Option<(usize, usize, usize, SampleFormat)> = None;
+    let mut position = 12u64;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        reader
+            .read_exact(&mut chunk_header)
+            .map_err(|_| "No data chunk found in wave file".to_string())?;
+        position += 8;
+
+        let chunk_id = &chunk_header[0..4];
+        let chunk_size = u32::from_le_bytes([
+            chunk_header[4],
+            chunk_header[5],
+            chunk_header[6],
+            chunk_header[7],
+        ]) as u64;
+
+        if chunk_id == b"fmt " {
+            let mut fmt = vec![0u8; chunk_size as usize];
+            reader
+                .read_exact(&mut fmt)
+                .map_err(|_| "Truncated fmt chunk".to_string())?;
+            format = Some(parse_fmt_chunk(&fmt)?);
+        }
+
+        if chunk_id == b"data" {
+            let (channels, sample_rate, bytes_per_sample, sample_format) =
+                format.ok_or("Wave file has no fmt chunk before its data")?;
+
+            // Streaming writers leave the size as 0 or 0xFFFFFFFF, so never
+            // trust it past the end of the file.
+            let available = file_len.saturating_sub(position);
+            let data_len = if chunk_size == 0 || chunk_size > available {
+                available
+            } else {
+                chunk_size
+            };
+            return Ok(WavLayout {
+                data_offset: position,
+                data_len,
+                channels,
+                sample_rate,
+                bytes_per_sample,
+                sample_format,
+            });
+        }
+
+        let skip = chunk_size + (chunk_size & 1);
+        position += skip;
+        reader
+            .seek(SeekFrom::Start(position))
+            .map_err(|e| format!("Could not skip chunk: {}", e))?;
+    }
+}
+
+fn parse_fmt_chunk(fmt: &[u8]) -> Result<(usize, usize, usize, SampleFormat), String> {
+    if fmt.len() < 16 {
+        return Err("fmt chunk is too short".to_string());
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+
+    let mut format_tag = u16_at(0);
+    let channels = u16_at(2) as usize;
+    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]) as usize;
+    let block_align = u16_at(12) as usize;
+
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        if fmt.len() < 26 {
+            return Err("Extensible fmt chunk is too short".to_string());
+        }
+        // The first two bytes of the sub format GUID carry the real format tag
+        format_tag = u16_at(24);
+    }
+
+    if channels == 0 || !block_align.is_multiple_of(channels) {
+        return Err("Invalid channel layout in fmt chunk".to_string());
+    }
+    let bytes_per_sample = block_align / channels;
+
+    let sample_format = match (format_tag, bytes_per_sample) {
+        (WAVE_FORMAT_PCM, 1..=4) => SampleFormat::Int,
+        (WAVE_FORMAT_IEEE_FLOAT, 4 | 8) => SampleFormat::Float,
+        _ => {
+            return Err(format!(
+                "Unsupported wave format {:#06x} with {} bytes per sample",
+                format_tag, bytes_per_sample
+            ));
+        }
+    };
+
+    Ok((channels, sample_rate, bytes_per_sample, sample_format))
+}
+
+/// Streams PCM frames straight from a wave file's data chunk.
+pub struct WavDecoder {
+    file: BufReader<File>,
+    layout: WavLayout,
+    buffer: Vec<u8>,
+    position: u64,
+}
+
+impl Decoder for WavDecoder {
+    fn open(path: &Path) -> Result<Self, String> {
+        let layout = read_layout(path)?;
+        let file = File::open(path).map_err(|e| format!("Could not open file: {}", e))?;
+        let mut file = BufReader::new(file);
+        file.seek(SeekFrom::Start(layout.data_offset))
+            .map_err(|e| format!("Could not seek past header: {}", e))?;
+
+        Ok(WavDecoder {
+            file,
+            layout,
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+
+    fn spec(&self) -> AudioSpec {
+        AudioSpec {
+            channels: self.layout.channels,
+            sample_rate: self.layout.sample_rate,
+        }
+    }
+
+    fn duration(&self) -> u64 {
+        self.layout.data_len / self.layout.frame_size() as u64
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        // Trailing chunks (LIST, id3, ...) may follow the audio data
+        let remaining = self.duration().saturating_sub(self.position) as usize;
+        let frames = (buffer.len() / self.layout.channels).min(remaining);
+        let frame_size = self.layout.frame_size();
+
+        self.buffer.resize(frames * frame_size, 0);
+        if self.file.read_exact(&mut self.buffer).is_err() {
+            return 0;
+        }
+
+        for (raw, frame) in self
+            .buffer
+            .chunks_exact(frame_size)
+            .zip(buffer.chunks_exact_mut(self.layout.channels))
+        {
+            self.layout.decode_frame(raw, frame);
+        }
+        self.position += frames as u64;
+        frames
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        let frame = frame.min(self.duration());
+        let byte_position = self.layout.data_offset + frame * self.layout.frame_size() as u64;
+        self.file
+            .seek(SeekFrom::Start(byte_position))
+            .map_err(|e| format!("Could not seek: {}", e))?;
+        self.position = frame;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn fmt_body(format_tag: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut body = Vec::new();
+        body.extend(format_tag.to_le_bytes());
+        body.extend(channels.to_le_bytes());
+        body.extend(sample_rate.to_le_bytes());
+        body.extend((sample_rate * block_align as u32).to_le_bytes());
+        body.extend(block_align.to_le_bytes());
+        body.extend(bits.to_le_bytes());
+        body
+    }
+
+    fn pcm_16_stereo() -> Vec<u8> {
+        chunk(b"fmt ", &fmt_body(WAVE_FORMAT_PCM, 2, 44100, 16))
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((body.len() as u32 + 4).to_le_bytes());
+        bytes.extend(b"WAVE");
+        bytes.extend(body);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Result<WavLayout, String> {
+        parse_layout(&mut Cursor::new(bytes), bytes.len() as u64)
+    }
+
+    #[test]
+    fn skips_metadata_chunks_before_data() {
+        let bytes = riff(&[
+            chunk(b"JUNK", &[0; 28]),
+            pcm_16_stereo(),
+            chunk(b"bext", &[0; 602]),
+            chunk(b"fact", &1000u32.to_le_bytes()),
+            chunk(b"LIST", b"INFOISFT\x06\0\0\0Logic\0"),
+            chunk(b"data", &[0; 400]),
+        ]);
+        let layout = parse(&bytes).unwrap();
+        assert_eq!(layout.data_offset as usize, bytes.len() - 400);
+        assert_eq!(layout.data_len, 400);
+        assert_eq!(layout.channels, 2);
+        assert_eq!(layout.sample_rate, 44100);
+        assert_eq!(layout.bytes_per_sample, 2);
+        assert_eq!(layout.sample_format, SampleFormat::Int);
+    }
+
+    #[test]
+    fn skips_pad_byte_after_odd_sized_chunk() {
+        let bytes = riff(&[
+            pcm_16_stereo(),
+            chunk(b"LIST", &[1; 13]),
+            chunk(b"data", &[0; 8]),
+        ]);
+        let layout = parse(&bytes).unwrap();
+        assert_eq!(layout.data_offset as usize, bytes.len() - 8);
+        assert_eq!(layout.data_len, 8);
+    }
+
+    #[test]
+    fn reads_extensible_24_bit_in_32_bit_words() {
+        let mut fmt = fmt_body(WAVE_FORMAT_EXTENSIBLE, 2, 48000, 32);
+        fmt.extend(22u16.to_le_bytes());
+        fmt.extend(24u16.to_le_bytes());
+        fmt.extend(3u32.to_le_bytes());
+        fmt.extend(WAVE_FORMAT_PCM.to_le_bytes());
+        fmt.extend(b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71");
+
+        let mut data = Vec::new();
+        data.extend((0x40_0000i32 << 8).to_le_bytes());
+        data.extend((-0x40_0000i32 << 8).to_le_bytes());
+        let bytes = riff(&[chunk(b"fmt ", &fmt), chunk(b"data", &data)]);
+
+        let layout = parse(&bytes).unwrap();
+        assert_eq!(layout.bytes_per_sample, 4);
+        assert_eq!(layout.sample_format, SampleFormat::Int);
+        let mut frame = [0.0; 2];
+        layout.decode_frame(&data, &mut frame);
+        assert_eq!(frame, [0.5, -0.5]);
+    }
+
+    #[test]
+    fn ignores_chunks_after_data() {
+        let bytes = riff(&[
+            pcm_16_stereo(),
+            chunk(b"data", &[0; 40]),
+            chunk(b"id3 ", &[0; 128]),
+        ]);
+        let layout = parse(&bytes).unwrap();
+        assert_eq!(layout.data_len, 40);
+    }
+
+    #[test]
+    fn streamed_data_size_runs_to_end_of_file() {
+        for size in [0u32, 0xFFFF_FFFF] {
+            let mut bytes = riff(&[pcm_16_stereo()]);
+            bytes.extend(b"data");
+            bytes.extend(size.to_le_bytes());
+            bytes.extend([0; 64]);
+            let layout = parse(&bytes).unwrap();
+            assert_eq!(layout.data_len, 64);
+        }
+    }
+
+    #[test]
+    fn rejects_data_without_fmt() {
+        let bytes = riff(&[chunk(b"data", &[0; 8])]);
+        assert!(parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn parses_float_fmt() {
+        let format = parse_fmt_chunk(&fmt_body(WAVE_FORMAT_IEEE_FLOAT, 1, 96000, 64)).unwrap();
+        assert_eq!(format, (1, 96000, 8, SampleFormat::Float));
+        assert!(parse_fmt_chunk(&fmt_body(WAVE_FORMAT_IEEE_FLOAT, 1, 96000, 16)).is_err());
+        assert!(parse_fmt_chunk(&[0; 12]).is_err());
+    }
+}